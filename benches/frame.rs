@@ -0,0 +1,44 @@
+//! Parsing throughput of the RESP frame decoder on a pipeline of commands,
+//! the way `Connection::parse_frame` sees it.
+
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use libactionkv::server_helpers::Frame;
+
+/// `count` pipelined `SET key:N <value>` commands in one buffer.
+fn pipeline(count: usize, value_size: usize) -> Vec<u8> {
+    let value = "x".repeat(value_size);
+    let mut buf = Vec::new();
+    for i in 0..count {
+        let key = format!("key:{:08}", i);
+        buf.extend_from_slice(
+            format!("*3\r\n$3\r\nset\r\n${}\r\n{}\r\n${}\r\n{}\r\n", key.len(), key, value.len(), value).as_bytes(),
+        );
+    }
+    buf
+}
+
+fn parse(c: &mut Criterion) {
+    const COMMANDS: usize = 1_000;
+    let mut group = c.benchmark_group("frame");
+    for &size in &[16usize, 1024] {
+        let buf = pipeline(COMMANDS, size);
+        group.throughput(Throughput::Elements(COMMANDS as u64));
+        group.bench_function(format!("check_and_parse/{}", size), |b| {
+            b.iter(|| {
+                let mut src = Cursor::new(&buf[..]);
+                while (src.position() as usize) < buf.len() {
+                    let start = src.position();
+                    Frame::check(&mut src).unwrap();
+                    src.set_position(start);
+                    Frame::parse(&mut src).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
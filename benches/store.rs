@@ -0,0 +1,190 @@
+//! Throughput of the storage engine without the server in front of it.
+//!
+//! Run with `cargo bench`; criterion keeps the previous run around, so a
+//! second run reports the change.
+
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use libactionkv::{IndexKind, OpenConfig, AKVMEM};
+
+/// A data file under the system temp directory, removed when dropped.
+struct TempStore {
+    path: PathBuf,
+}
+
+impl TempStore {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("akv-bench-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        TempStore { path }
+    }
+
+    fn open(&self) -> AKVMEM {
+        libactionkv::open(&self.path).expect("Unable to open bench store")
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key:{:08}", i).into_bytes()
+}
+
+/// Writes `records` distinct keys with `value_size` byte values to `path`.
+fn populate(path: &Path, records: u64, value_size: usize) -> AKVMEM {
+    let mut store = libactionkv::open(path).expect("Unable to open bench store");
+    let value = vec![b'x'; value_size];
+    for i in 0..records {
+        store.insert(&key(i), &value).expect("Unable to populate bench store");
+    }
+    store
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for &size in &[16usize, 256, 4096, 65536] {
+        let tmp = TempStore::new(&format!("insert-{}", size));
+        let mut store = tmp.open();
+        let value = vec![b'x'; size];
+        let mut i = 0;
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                i += 1;
+                store.insert(&key(i), &value).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The same 10k small records written with `insert` in a loop and with one
+/// `bulk_insert`, which only flushes at the end.
+fn bulk_insert(c: &mut Criterion) {
+    const RECORDS: u64 = 10_000;
+    let mut group = c.benchmark_group("bulk_insert");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(RECORDS));
+    let value = vec![b'x'; 64];
+    let tmp = TempStore::new("bulk-loop");
+    let mut store = tmp.open();
+    group.bench_function("insert_loop", |b| {
+        b.iter(|| {
+            for i in 0..RECORDS {
+                store.insert(&key(i), &value).unwrap();
+            }
+        })
+    });
+    let tmp = TempStore::new("bulk");
+    let mut store = tmp.open();
+    group.bench_function("bulk_insert", |b| {
+        b.iter(|| store.bulk_insert((0..RECORDS).map(|i| (key(i), value.clone()))).unwrap())
+    });
+    group.finish();
+}
+
+/// 10k small records written with `insert`, each going to the file on its
+/// own and gathered in a 64 KiB write buffer.
+fn write_buffer(c: &mut Criterion) {
+    const RECORDS: u64 = 10_000;
+    let mut group = c.benchmark_group("write_buffer");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(RECORDS));
+    let value = vec![b'x'; 64];
+    for &capacity in &[0usize, 64 * 1024] {
+        let tmp = TempStore::new(&format!("write-buffer-{}", capacity));
+        let config = OpenConfig { write_buffer: capacity, ..OpenConfig::default() };
+        let mut store = libactionkv::open_with_options(&tmp.path, config).expect("Unable to open bench store");
+        group.bench_with_input(BenchmarkId::from_parameter(capacity), &capacity, |b, _| {
+            b.iter(|| {
+                for i in 0..RECORDS {
+                    store.insert(&key(i), &value).unwrap();
+                }
+                store.flush().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    const RECORDS: u64 = 100_000;
+    let tmp = TempStore::new("get");
+    let store = populate(&tmp.path, RECORDS, 128);
+    // A fixed LCG keeps the key order random but the same from run to run.
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    c.bench_function("get/random", |b| {
+        b.iter(|| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            store.get(&key((state >> 33) % RECORDS)).unwrap()
+        })
+    });
+}
+
+/// Point lookups and a prefix scan over the same 100k keys with each kind
+/// of index. The scan matches 100 of them.
+fn index_kind(c: &mut Criterion) {
+    const RECORDS: u64 = 100_000;
+    let tmp = TempStore::new("index-kind");
+    drop(populate(&tmp.path, RECORDS, 16));
+    let mut group = c.benchmark_group("index_kind");
+    for (name, kind) in [("hashed", IndexKind::Hashed), ("ordered", IndexKind::Ordered)] {
+        let config = OpenConfig { index_kind: kind, ..OpenConfig::default() };
+        let mut store = libactionkv::open_with_options(&tmp.path, config).expect("Unable to open bench store");
+        store.load().unwrap();
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        group.bench_function(BenchmarkId::new("get", name), |b| {
+            b.iter(|| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                store.get(&key((state >> 33) % RECORDS)).unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("scan_prefix", name), |b| {
+            b.iter(|| store.scan_prefix(b"key:00042").unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+    group.sample_size(10);
+    for &records in &[10_000u64, 100_000] {
+        let tmp = TempStore::new(&format!("load-{}", records));
+        drop(populate(&tmp.path, records, 128));
+        group.throughput(Throughput::Elements(records));
+        group.bench_with_input(BenchmarkId::from_parameter(records), &records, |b, _| {
+            b.iter_batched(|| tmp.open(), |mut store| store.load().unwrap(), BatchSize::PerIteration)
+        });
+    }
+    group.finish();
+}
+
+/// Loading 100k records of 128 bytes through read buffers of a few sizes.
+fn read_buffer(c: &mut Criterion) {
+    const RECORDS: u64 = 100_000;
+    let tmp = TempStore::new("read-buffer");
+    drop(populate(&tmp.path, RECORDS, 128));
+    let mut group = c.benchmark_group("read_buffer");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(RECORDS));
+    for &capacity in &[8usize << 10, 64 << 10, 1 << 20] {
+        let config = OpenConfig { read_buffer: capacity, ..OpenConfig::default() };
+        group.bench_with_input(BenchmarkId::new("load", capacity), &capacity, |b, _| {
+            b.iter_batched(
+                || libactionkv::open_with_options(&tmp.path, config).expect("Unable to open bench store"),
+                |mut store| store.load().unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, bulk_insert, write_buffer, get, index_kind, load, read_buffer);
+criterion_main!(benches);
@@ -1,100 +1,2297 @@
-use std::{env, path::{ Path }, sync::{Arc, Mutex}};
-use bytes::{Bytes, BytesMut};
-use tokio::{net::{TcpStream, TcpListener}};
+use std::{collections::HashMap, env, path::{ Path }, process, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(feature = "tls")]
+use tokio_rustls::{rustls::{self, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}}, TlsAcceptor};
 
 
-use libactionkv::AKVMEM;
+use libactionkv::{Checksum, EvictionPolicy, ExportReader, Exporter, OpenConfig, ValueType, AKVMEM, HEADER_LEN, WRONG_TYPE};
+use libactionkv::server_helpers::COMMAND_NAMES;
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+use rand::Rng;
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
 USAGE:
-    akv_mem.exe FILE 
-    akv_mem.exe FILE 
-    akv_mem.exe FILE 
-    akv_mem.exe FILE 
+    akv_mem.exe FILE [OPTIONS]
+
+OPTIONS:
+    --dir PATH                   Directory a relative FILE is resolved against
+                                 (default the current directory)
+    --auto-compact yes|no        Compact the data file in the background (default yes)
+    --auto-compact-ratio RATIO   Dead to total byte ratio that triggers it (default 0.5)
+    --unixsocket PATH            Also listen on a unix domain socket
+    --tls-cert PATH              PEM certificate chain; TCP clients must use TLS
+    --tls-key PATH               PEM private key for --tls-cert
+    --metrics-port PORT          Serve Prometheus metrics over HTTP on this port
+    --requirepass PASSWORD       Require clients to AUTH before any other command
+    --databases N                Number of databases SELECT can pick from (default 1)
+    --shards N                   Split each database into N files by key hash, so
+                                 writes to different shards run in parallel (default 1)
+    --notify-keyspace-events yes|no
+                                 Publish key changes to __keyspace@DB__:KEY (default no)
+    --maxmemory BYTES            Budget for each database's live records
+    --maxmemory-policy noeviction|allkeys-random|allkeys-lru
+                                 What writes over the budget do (default noeviction)
+    --checksum crc32|xxhash      Record checksum for new data files (default crc32)
+    --verify-on-read yes|no      Check record checksums on every read (default yes)
+    --group-commit-ms MS         Acknowledge writes only after an fsync, batching
+                                 the writes of up to MS milliseconds into one
+    --group-commit-writes N      Sync early once N writes are waiting (default 128)
+    --maxclients N               Refuse connections past N open ones
+    --timeout SECONDS            Close connections idle for this long (default 0, never)
+    --noop-commands LIST         Comma separated commands, with their subcommand if
+                                 any, to answer OK and ignore (default
+                                 \"client setinfo\")
 
 ";
 
 #[cfg(not(target_os = "windows"))]
 const USAGE: &str = "
 USAGE:
-    akv_mem FILE 
-    akv_mem FILE 
-    akv_mem FILE 
-    akv_mem FILE 
+    akv_mem FILE [OPTIONS]
+
+OPTIONS:
+    --dir PATH                   Directory a relative FILE is resolved against
+                                 (default the current directory)
+    --auto-compact yes|no        Compact the data file in the background (default yes)
+    --auto-compact-ratio RATIO   Dead to total byte ratio that triggers it (default 0.5)
+    --unixsocket PATH            Also listen on a unix domain socket
+    --tls-cert PATH              PEM certificate chain; TCP clients must use TLS
+    --tls-key PATH               PEM private key for --tls-cert
+    --metrics-port PORT          Serve Prometheus metrics over HTTP on this port
+    --requirepass PASSWORD       Require clients to AUTH before any other command
+    --databases N                Number of databases SELECT can pick from (default 1)
+    --shards N                   Split each database into N files by key hash, so
+                                 writes to different shards run in parallel (default 1)
+    --notify-keyspace-events yes|no
+                                 Publish key changes to __keyspace@DB__:KEY (default no)
+    --maxmemory BYTES            Budget for each database's live records
+    --maxmemory-policy noeviction|allkeys-random|allkeys-lru
+                                 What writes over the budget do (default noeviction)
+    --checksum crc32|xxhash      Record checksum for new data files (default crc32)
+    --verify-on-read yes|no      Check record checksums on every read (default yes)
+    --group-commit-ms MS         Acknowledge writes only after an fsync, batching
+                                 the writes of up to MS milliseconds into one
+    --group-commit-writes N      Sync early once N writes are waiting (default 128)
+    --maxclients N               Refuse connections past N open ones
+    --timeout SECONDS            Close connections idle for this long (default 0, never)
+    --noop-commands LIST         Comma separated commands, with their subcommand if
+                                 any, to answer OK and ignore (default
+                                 \"client setinfo\")
 
 ";
 
+/// How often the auto-compaction task checks the dead byte ratio.
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often each data file is checked to still be where it was opened.
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Config {
+    file: String,
+    dir: Option<String>,
+    auto_compact: bool,
+    auto_compact_ratio: f64,
+    unixsocket: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    metrics_port: Option<u16>,
+    requirepass: Option<String>,
+    databases: usize,
+    shards: usize,
+    notify_keyspace_events: bool,
+    maxmemory: Option<u64>,
+    maxmemory_policy: EvictionPolicy,
+    checksum: Checksum,
+    verify_on_read: bool,
+    group_commit_ms: Option<u64>,
+    group_commit_writes: usize,
+    limits: ClientLimits,
+    noop_commands: Vec<String>,
+}
+
+impl Config {
+    fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut file = None;
+        let mut config = Config {
+            file: String::new(),
+            dir: None,
+            auto_compact: true,
+            auto_compact_ratio: 0.5,
+            unixsocket: None,
+            tls_cert: None,
+            tls_key: None,
+            metrics_port: None,
+            requirepass: None,
+            databases: 1,
+            shards: 1,
+            notify_keyspace_events: false,
+            maxmemory: None,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            checksum: Checksum::Crc32,
+            verify_on_read: true,
+            group_commit_ms: None,
+            group_commit_writes: 128,
+            limits: ClientLimits::default(),
+            noop_commands: vec!["client setinfo".to_string()],
+        };
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                if file.replace(arg).is_some() {
+                    return Err("Only one FILE may be given".to_string())
+                }
+                continue;
+            }
+            match arg.as_str() {
+                "--dir" => config.dir = Some(next_value(&mut args, &arg)?),
+                "--notify-keyspace-events" => {
+                    config.notify_keyspace_events = parse_yes_no(&arg, &next_value(&mut args, &arg)?)?
+                }
+                "--auto-compact" => config.auto_compact = parse_yes_no(&arg, &next_value(&mut args, &arg)?)?,
+                "--auto-compact-ratio" => {
+                    config.auto_compact_ratio = next_value(&mut args, &arg)?
+                        .parse()
+                        .map_err(|_| format!("{} must be a number", arg))?
+                }
+                "--unixsocket" => config.unixsocket = Some(next_value(&mut args, &arg)?),
+                "--tls-cert" => config.tls_cert = Some(next_value(&mut args, &arg)?),
+                "--tls-key" => config.tls_key = Some(next_value(&mut args, &arg)?),
+                "--databases" => {
+                    config.databases = next_value(&mut args, &arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("{} must be a positive number", arg))?
+                }
+                "--shards" => {
+                    config.shards = next_value(&mut args, &arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("{} must be a positive number", arg))?
+                }
+                "--maxmemory" => {
+                    config.maxmemory = Some(next_value(&mut args, &arg)?
+                        .parse()
+                        .map_err(|_| format!("{} must be a number of bytes", arg))?)
+                }
+                "--maxmemory-policy" => {
+                    config.maxmemory_policy = match next_value(&mut args, &arg)?.as_str() {
+                        "noeviction" => EvictionPolicy::NoEviction,
+                        "allkeys-random" => EvictionPolicy::AllKeysRandom,
+                        "allkeys-lru" => EvictionPolicy::AllKeysLru,
+                        _ => return Err(format!("{} must be noeviction, allkeys-random or allkeys-lru", arg)),
+                    }
+                }
+                "--checksum" => {
+                    config.checksum = match next_value(&mut args, &arg)?.as_str() {
+                        "crc32" => Checksum::Crc32,
+                        "xxhash" => Checksum::XxHash,
+                        _ => return Err(format!("{} must be crc32 or xxhash", arg)),
+                    }
+                }
+                "--verify-on-read" => config.verify_on_read = parse_yes_no(&arg, &next_value(&mut args, &arg)?)?,
+                "--group-commit-ms" => {
+                    config.group_commit_ms = Some(next_value(&mut args, &arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&ms| ms > 0)
+                        .ok_or_else(|| format!("{} must be a positive number", arg))?)
+                }
+                "--group-commit-writes" => {
+                    config.group_commit_writes = next_value(&mut args, &arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("{} must be a positive number", arg))?
+                }
+                "--maxclients" => {
+                    config.limits.maxclients = Some(next_value(&mut args, &arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("{} must be a positive number", arg))?)
+                }
+                "--timeout" => {
+                    let seconds: u64 = next_value(&mut args, &arg)?
+                        .parse()
+                        .map_err(|_| format!("{} must be a number of seconds", arg))?;
+                    config.limits.timeout = Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero());
+                }
+                "--requirepass" => config.requirepass = Some(next_value(&mut args, &arg)?),
+                "--noop-commands" => {
+                    config.noop_commands = next_value(&mut args, &arg)?
+                        .split(',')
+                        .map(|command| command.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+                        .filter(|command| !command.is_empty())
+                        .collect()
+                }
+                "--metrics-port" => {
+                    config.metrics_port = Some(next_value(&mut args, &arg)?
+                        .parse()
+                        .map_err(|_| format!("{} must be a port number", arg))?)
+                }
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+        config.file = file.ok_or_else(|| "Missing FILE".to_string())?;
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key must be given together".to_string())
+        }
+        Ok(config)
+    }
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, arg: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", arg))
+}
+
+fn parse_yes_no(arg: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("{} must be yes or no", arg)),
+    }
+}
+
+/// The logical databases SELECT picks from. Each one is split by key hash
+/// into `shards` stores with a data file and lock of their own, so writes to
+/// keys in different shards don't wait on each other. Database `db` owns the
+/// stores from `db * shards` on.
+struct Keyspace {
+    stores: Vec<Arc<Mutex<AKVMEM>>>,
+    shards: usize,
+}
+
+type Databases = Arc<Keyspace>;
+
+impl Keyspace {
+    fn databases(&self) -> usize {
+        self.stores.len() / self.shards
+    }
+
+    /// Index into `stores` of the shard of database `db` that owns `key`.
+    fn slot(&self, db: usize, key: &[u8]) -> usize {
+        db * self.shards + shard_of(key, self.shards)
+    }
+
+    /// Every shard of database `db`.
+    fn shards(&self, db: usize) -> &[Arc<Mutex<AKVMEM>>] {
+        &self.stores[db * self.shards..(db + 1) * self.shards]
+    }
+}
+
+/// Which of `shards` owns `key`. Where a key lives is baked into the data
+/// files, so this uses XXH64 rather than the std hasher, whose output may
+/// change from one release to the next.
+fn shard_of(key: &[u8], shards: usize) -> usize {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(key);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// Absolute path of the data file `file`, resolved against `dir` if it is
+/// relative, or against the current directory without a `dir`. The
+/// directory has to exist; the file doesn't.
+fn resolve_file(dir: Option<&str>, file: &str) -> Result<String, String> {
+    let dir = match dir {
+        Some(dir) => Path::new(dir).canonicalize().map_err(|err| format!("Unable to use --dir {}: {}", dir, err))?,
+        None => env::current_dir().map_err(|err| format!("Unable to read the current directory: {}", err))?,
+    };
+    let path = dir.join(file);
+    path.to_str().map(str::to_string).ok_or_else(|| format!("{} is not valid UTF-8", path.display()))
+}
+
+/// Data file for database `index`. Database 0 uses FILE itself so that
+/// single database setups keep their existing file.
+fn database_file(file: &str, index: usize) -> String {
+    if index == 0 { file.to_string() } else { format!("{}.{}", file, index) }
+}
+
+/// Data file for shard `shard` of the database in `file`. Shard 0 uses the
+/// file itself, so an unsharded setup keeps its existing file.
+fn shard_file(file: &str, shard: usize) -> String {
+    if shard == 0 { file.to_string() } else { format!("{}.shard{}", file, shard) }
+}
+
+/// Writes of one database waiting for the next batched fsync. With group
+/// commit on, a write is only acknowledged once a sync that started after it
+/// has finished, so concurrent writes share one fsync instead of paying for
+/// one each.
+struct GroupCommit {
+    waiting: Mutex<Vec<oneshot::Sender<Result<(), String>>>>,
+    /// Woken when enough writes are waiting to sync before the window ends.
+    full: Notify,
+    max_writes: usize,
+}
+
+/// The group commit of each store, by its index in `Keyspace::stores`, when
+/// it is turned on.
+type GroupCommits = Arc<Vec<Arc<GroupCommit>>>;
+
+impl GroupCommit {
+    fn new(max_writes: usize) -> Self {
+        GroupCommit { waiting: Mutex::new(Vec::new()), full: Notify::new(), max_writes }
+    }
+
+    /// Queues a write that has already been handed to the OS. The receiver
+    /// resolves once it is on disk, or with the error the sync failed with.
+    fn wait(&self) -> oneshot::Receiver<Result<(), String>> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        waiting.push(tx);
+        if waiting.len() >= self.max_writes {
+            self.full.notify_one();
+        }
+        rx
+    }
+}
+
+/// Server-wide counters reported by INFO and the metrics endpoint.
+struct Stats {
+    started: Instant,
+    connected_clients: AtomicU64,
+    total_commands: AtomicU64,
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    /// GETs that found a value.
+    hits: AtomicU64,
+    /// GETs of a missing key.
+    misses: AtomicU64,
+    /// Key and value bytes sent by SET, UPDATE and SETEX.
+    bytes_written: AtomicU64,
+    /// How long each kind of command takes, by name.
+    latency: HashMap<&'static str, Histogram>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            connected_clients: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            sets: AtomicU64::new(0),
+            deletes: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            latency: COMMAND_NAMES.iter().map(|&name| (name, Histogram::new())).collect(),
+        }
+    }
+
+    fn count(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn record_latency(&self, command: &str, elapsed: Duration) {
+        if let Some(histogram) = self.latency.get(command) {
+            histogram.record(elapsed.as_micros().min(u128::from(u64::MAX)) as u64);
+        }
+    }
+}
+
+/// Sub-buckets each power of two is split into, which keeps a recorded
+/// value within an eighth of the bucket it is reported as.
+const LATENCY_SUB_BUCKETS: u64 = 8;
+/// Latencies are recorded up to 2^40 microseconds, about 12 days; anything
+/// longer lands in the last bucket.
+const LATENCY_MAX_EXPONENT: u32 = 40;
+const LATENCY_BUCKETS: usize = ((LATENCY_MAX_EXPONENT - 2) as u64 * LATENCY_SUB_BUCKETS) as usize;
+
+/// Counts of latencies in microseconds, bucketed logarithmically so that
+/// recording one is a single atomic add and percentiles come out within
+/// 12.5%.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+}
 
+impl Histogram {
+    fn new() -> Self {
+        Histogram { buckets: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect() }
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < LATENCY_SUB_BUCKETS {
+            return micros as usize
+        }
+        let micros = micros.min((1 << LATENCY_MAX_EXPONENT) - 1);
+        let exponent = 63 - micros.leading_zeros();
+        let sub = (micros >> (exponent - 3)) & (LATENCY_SUB_BUCKETS - 1);
+        ((u64::from(exponent) - 2) * LATENCY_SUB_BUCKETS + sub) as usize
+    }
+
+    /// The largest value that lands in bucket `index`.
+    fn bucket_max(index: usize) -> u64 {
+        let index = index as u64;
+        if index < LATENCY_SUB_BUCKETS {
+            return index
+        }
+        let exponent = index / LATENCY_SUB_BUCKETS + 2;
+        let sub = index % LATENCY_SUB_BUCKETS;
+        ((LATENCY_SUB_BUCKETS + sub + 1) << (exponent - 3)) - 1
+    }
+
+    fn record(&self, micros: u64) {
+        self.buckets[Self::bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The latency `percentile` percent of recorded ones are at or under,
+    /// or None if nothing has been recorded.
+    fn percentile(&self, percentile: f64) -> Option<u64> {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None
+        }
+        let rank = ((percentile / 100.0 * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Self::bucket_max(index))
+            }
+        }
+        Some(Self::bucket_max(LATENCY_BUCKETS - 1))
+    }
+}
+
+/// Messages a subscriber can fall behind by before it starts missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// PUBLISH/SUBSCRIBE channels. A channel exists while someone is subscribed
+/// to it and is dropped by the first publish that finds nobody listening.
+struct PubSub {
+    channels: Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+    /// Whether writes publish keyspace notifications.
+    keyspace_events: bool,
+    /// What BLPOP waits on for each database and key someone is blocked on.
+    /// A push to the key wakes every waiter, which then race for the list.
+    list_waiters: Mutex<HashMap<(usize, String), broadcast::Sender<()>>>,
+}
+
+impl PubSub {
+    fn new(keyspace_events: bool) -> Self {
+        PubSub { channels: Mutex::new(HashMap::new()), keyspace_events, list_waiters: Mutex::new(HashMap::new()) }
+    }
+
+    /// Hears about every push to `key` in database `db` from now on. Hand it
+    /// back with `release_list_waiter` once done waiting.
+    fn list_waiter(&self, db: usize, key: &str) -> broadcast::Receiver<()> {
+        let mut waiters = self.list_waiters.lock().unwrap_or_else(PoisonError::into_inner);
+        waiters.entry((db, key.to_string())).or_insert_with(|| broadcast::channel(1).0).subscribe()
+    }
+
+    /// Drops `waiter` and forgets the key once nobody else waits on it.
+    fn release_list_waiter(&self, db: usize, key: &str, waiter: broadcast::Receiver<()>) {
+        let mut waiters = self.list_waiters.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(waiter);
+        let id = (db, key.to_string());
+        if waiters.get(&id).is_some_and(|sender| sender.receiver_count() == 0) {
+            waiters.remove(&id);
+        }
+    }
+
+    /// Wakes everyone blocked on `key` in database `db`.
+    fn wake_list_waiters(&self, db: usize, key: &str) {
+        let waiters = self.list_waiters.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(sender) = waiters.get(&(db, key.to_string())) {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Publishes `event` to `__keyspace@<db>__:<key>` when keyspace
+    /// notifications are turned on.
+    fn notify_keyspace(&self, db: usize, key: &str, event: &'static str) {
+        if self.keyspace_events {
+            self.publish(&format!("__keyspace@{}__:{}", db, key), Bytes::from(event));
+        }
+    }
+
+    fn subscribe(&self, channel: &str) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+        channels
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Sends `message` to everyone subscribed to `channel` and returns how
+    /// many that was.
+    fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+        let receivers = match channels.get(channel) {
+            Some(sender) => sender.send(message).unwrap_or(0),
+            None => return 0,
+        };
+        if receivers == 0 {
+            channels.remove(channel);
+        }
+        receivers
+    }
+}
+
+/// A connection's subscriptions. Each channel gets a task that forwards its
+/// messages to the connection; dropping this stops them all.
+struct Subscriptions {
+    tx: mpsc::UnboundedSender<(String, Bytes)>,
+    forwarders: HashMap<String, JoinHandle<()>>,
+}
+
+impl Subscriptions {
+    fn new() -> (Self, mpsc::UnboundedReceiver<(String, Bytes)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Subscriptions { tx, forwarders: HashMap::new() }, rx)
+    }
+
+    /// Subscribes to `channel` unless already subscribed, and returns the
+    /// number of channels subscribed to.
+    fn add(&mut self, pubsub: &PubSub, channel: &str) -> usize {
+        if !self.forwarders.contains_key(channel) {
+            let mut rx = pubsub.subscribe(channel);
+            let tx = self.tx.clone();
+            let name = channel.to_string();
+            let forwarder = tokio::spawn(async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(message) => {
+                            if tx.send((name.clone(), message)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!(channel = %name, missed, "subscriber fell behind");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+            self.forwarders.insert(channel.to_string(), forwarder);
+        }
+        self.forwarders.len()
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for forwarder in self.forwarders.values() {
+            forwarder.abort();
+        }
+    }
+}
+
+/// What every connection is served with besides the stores.
+#[derive(Debug, Clone, Default)]
+struct ServerSettings {
+    /// With a password set, every command but AUTH and QUIT is refused until
+    /// the client has sent it.
+    requirepass: Option<Arc<str>>,
+    limits: ClientLimits,
+    /// Commands, lowercase and followed by their subcommand if they have
+    /// one, that are answered with OK and otherwise ignored. Clients send
+    /// some housekeeping commands on connect and give up if they fail.
+    noop_commands: Arc<[String]>,
+}
+
+/// Bounds on the connections the server keeps open.
+#[derive(Debug, Clone, Copy, Default)]
+struct ClientLimits {
+    /// Connections past this many are told so and closed.
+    maxclients: Option<u64>,
+    /// Connections that send nothing for this long are closed. Subscribers
+    /// are exempt, as they are expected to sit and wait for messages.
+    timeout: Option<Duration>,
+}
+
+/// Counts a connection for as long as it is alive, even if its task panics.
+struct ClientGuard<'a>(&'a AtomicU64);
+
+impl<'a> ClientGuard<'a> {
+    fn new(clients: &'a AtomicU64) -> Self {
+        clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(clients)
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
-    let args: Vec<String>= env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
-
-    let path = Path::new(fname);
-    let mut store = libactionkv::open(path).expect("Unable to open path to database");
-    store.load().expect("Unable to load data");
-    let db = Arc::new(Mutex::new(store));
+    // RUST_LOG picks the verbosity, e.g. RUST_LOG=debug to see every command.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+    let mut config = Config::from_args(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(1)
+    });
+    // Resolved once up front, so the files opened, the files reported and
+    // any later change of directory can't disagree.
+    config.file = resolve_file(config.dir.as_deref(), &config.file).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1)
+    });
+    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap_or_else(|err| {
+        error!("Unable to bind 127.0.0.1:6379: {}", err);
+        process::exit(1)
+    });
+
+    let open_config = OpenConfig {
+        maxmemory: config.maxmemory,
+        eviction_policy: config.maxmemory_policy,
+        checksum: config.checksum,
+        verify_on_read: config.verify_on_read,
+        ..OpenConfig::default()
+    };
+    let stores = (0..config.databases).flat_map(|index| {
+        let file = database_file(&config.file, index);
+        // A file past the last shard means the data was split more ways
+        // than this, and its keys would silently go missing.
+        if Path::new(&shard_file(&file, config.shards)).exists() {
+            error!("{} was written with more than {} shards", file, config.shards);
+            process::exit(1)
+        }
+        (0..config.shards).map(move |shard| (shard_file(&file, shard), shard))
+    }).map(|(file, shard)| {
+        let mut store = libactionkv::open_with_options(Path::new(&file), open_config).expect("Unable to open path to database");
+        store.load().expect("Unable to load data");
+        if store.index.keys().any(|key| shard_of(key, config.shards) != shard) {
+            error!("{} holds keys of another shard; start with the --shards it was written with", file);
+            process::exit(1)
+        }
+        info!(file = %file, keys = store.index.len(), "database loaded");
+        Arc::new(Mutex::new(store))
+    }).collect();
+    let databases: Databases = Arc::new(Keyspace { stores, shards: config.shards });
+    let stats = Arc::new(Stats::new());
+    let pubsub = Arc::new(PubSub::new(config.notify_keyspace_events));
+    if config.auto_compact {
+        for db in databases.stores.iter() {
+            tokio::spawn(auto_compact(Arc::clone(db), config.auto_compact_ratio));
+        }
+    }
+    for db in databases.stores.iter() {
+        tokio::spawn(watch_file(Arc::clone(db)));
+    }
+    let group_commit: Option<GroupCommits> = config.group_commit_ms.map(|ms| {
+        Arc::new(databases.stores.iter().map(|db| {
+            let commit = Arc::new(GroupCommit::new(config.group_commit_writes));
+            tokio::spawn(group_commit(Arc::clone(db), Arc::clone(&commit), Duration::from_millis(ms)));
+            commit
+        }).collect())
+    });
+    let settings = ServerSettings {
+        requirepass: config.requirepass.as_deref().map(Arc::from),
+        limits: config.limits,
+        noop_commands: config.noop_commands.into(),
+    };
+    if let Some(port) = config.metrics_port {
+        serve_metrics(port, Arc::clone(&databases), Arc::clone(&stats)).await;
+    }
+    if let Some(socket_path) = &config.unixsocket {
+        serve_unix(
+            socket_path,
+            Arc::clone(&databases),
+            Arc::clone(&stats),
+            Arc::clone(&pubsub),
+            group_commit.clone(),
+            settings.clone(),
+        );
+    }
+    #[cfg(feature = "tls")]
+    let tls = config.tls_cert.as_ref().zip(config.tls_key.as_ref()).map(|(cert, key)| {
+        tls_acceptor(cert, key).unwrap_or_else(|err| {
+            error!("Unable to load TLS certificate: {}", err);
+            process::exit(1)
+        })
+    });
+    #[cfg(not(feature = "tls"))]
+    if config.tls_cert.is_some() {
+        eprintln!("--tls-cert needs a build with the tls feature");
+        process::exit(1)
+    }
+    info!("listening on 127.0.0.1:6379");
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Unable to accept connection: {}", err);
+                continue;
+            }
+        };
+        let span = info_span!("connection", %peer);
       
-        let db_clone = Arc::clone(&db);
+        let db_clone = Arc::clone(&databases);
+        let stats_clone = Arc::clone(&stats);
+        let pubsub_clone = Arc::clone(&pubsub);
+        let group_commit = group_commit.clone();
+        let settings = settings.clone();
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &tls {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(socket).await {
+                    Ok(stream) => process(stream, db_clone, stats_clone, pubsub_clone, group_commit, settings).await,
+                    Err(err) => warn!("TLS handshake failed: {}", err),
+                }
+            }.instrument(span));
+            continue;
+        }
         tokio::spawn(async move {
-            process(socket, db_clone).await
-        });
+            process(socket, db_clone, stats_clone, pubsub_clone, group_commit, settings).await
+        }.instrument(span));
 
     }
 
 }
 
-async fn process(socket: TcpStream, db: Arc<Mutex<AKVMEM>>)  {
+#[cfg(feature = "tls")]
+fn tls_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {}", cert, err))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|err| format!("{}: {}", key, err))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(unix)]
+fn serve_unix(
+    socket_path: &str,
+    databases: Databases,
+    stats: Arc<Stats>,
+    pubsub: Arc<PubSub>,
+    group_commit: Option<GroupCommits>,
+    settings: ServerSettings,
+) {
+    // A socket file left behind by a previous run would make bind fail.
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).expect("Unable to bind unix socket");
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("Unable to accept unix socket connection: {}", err);
+                    continue;
+                }
+            };
+            let span = info_span!("connection", peer = ?peer);
+
+            let db_clone = Arc::clone(&databases);
+            let stats_clone = Arc::clone(&stats);
+            let pubsub_clone = Arc::clone(&pubsub);
+            let group_commit = group_commit.clone();
+            let settings = settings.clone();
+            tokio::spawn(async move {
+                process(socket, db_clone, stats_clone, pubsub_clone, group_commit, settings).await
+            }.instrument(span));
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn serve_unix(
+    _socket_path: &str,
+    _databases: Databases,
+    _stats: Arc<Stats>,
+    _pubsub: Arc<PubSub>,
+    _group_commit: Option<GroupCommits>,
+    _settings: ServerSettings,
+) {
+    eprintln!("--unixsocket is only supported on unix platforms");
+    process::exit(1)
+}
+
+/// Answers every HTTP request on `port` with the counters in Prometheus text
+/// format. There is nothing else to serve, so the request itself is only
+/// checked for its path.
+async fn serve_metrics(port: u16, databases: Databases, stats: Arc<Stats>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap_or_else(|err| {
+        error!("Unable to bind metrics port {}: {}", port, err);
+        process::exit(1)
+    });
+    info!("serving metrics on 127.0.0.1:{}/metrics", port);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("Unable to accept metrics connection: {}", err);
+                    continue;
+                }
+            };
+            let databases = Arc::clone(&databases);
+            let stats = Arc::clone(&stats);
+            tokio::spawn(async move {
+                let mut request = [0; 1024];
+                let n = match socket.read(&mut request).await {
+                    Ok(n) => n,
+                    Err(_) => return,
+                };
+                let response = if request[..n].starts_with(b"GET /metrics ") {
+                    let keys = databases
+                        .stores
+                        .iter()
+                        .map(|db| db.lock().unwrap_or_else(PoisonError::into_inner).index.len())
+                        .sum();
+                    let body = metrics(keys, &stats);
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body,
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                };
+                if let Err(err) = socket.write_all(response.as_bytes()).await {
+                    debug!("Unable to send metrics: {}", err);
+                }
+            });
+        }
+    });
+}
+
+/// Periodically compacts the store once enough of it is dead. The lock is
+/// only held to start and to finish; copying the live records, which is the
+/// slow part, runs while clients keep being served. Every step runs on the
+/// blocking pool, since starting creates a file and finishing syncs and
+/// renames one.
+async fn auto_compact(db: Arc<Mutex<AKVMEM>>, ratio: f64) {
+    let mut interval = tokio::time::interval(COMPACTION_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let store = Arc::clone(&db);
+        let begun = tokio::task::spawn_blocking(move || {
+            let db = store.lock().unwrap_or_else(PoisonError::into_inner);
+            match db.dead_ratio() {
+                Ok(current) if current >= ratio => {
+                    info!(dead_ratio = %current, "starting compaction");
+                    Some(db.begin_compaction())
+                }
+                _ => None,
+            }
+        }).await.unwrap_or_else(|err| Some(Err(err.into())));
+        let mut compaction = match begun {
+            Some(Ok(compaction)) => compaction,
+            Some(Err(err)) => {
+                error!("Unable to start compaction: {}", err);
+                continue;
+            }
+            None => continue,
+        };
+        let store = Arc::clone(&db);
+        let result = tokio::task::spawn_blocking(move || {
+            compaction.copy_live()?;
+            store.lock().unwrap_or_else(PoisonError::into_inner).finish_compaction(compaction)
+        }).await.unwrap_or_else(|err| Err(err.into()));
+        if let Err(err) = result {
+            error!("Compaction failed: {}", err);
+        }
+    }
+}
+
+/// Notices when the data file of `db` is deleted out from under the server,
+/// e.g. by log rotation, and writes it back from the open copy. If it was
+/// replaced instead, or can't be written, the store refuses writes and the
+/// error is logged once until the file is back.
+async fn watch_file(db: Arc<Mutex<AKVMEM>>) {
+    let mut interval = tokio::time::interval(FILE_CHECK_INTERVAL);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        let db = Arc::clone(&db);
+        // Restoring copies the whole log, so it stays off the runtime.
+        let checked = tokio::task::spawn_blocking(move || {
+            let mut db = db.lock().unwrap_or_else(PoisonError::into_inner);
+            if db.check_file()? {
+                return Ok(None)
+            }
+            db.restore_file().map(|()| Some(db.path().display().to_string()))
+        }).await;
+        match checked {
+            Ok(Ok(None)) => failing = false,
+            Ok(Ok(Some(path))) => {
+                warn!(path = %path, "data file was deleted while open; wrote it back");
+                failing = false;
+            }
+            Ok(Err(err)) if !failing => {
+                error!("Data file check failed, refusing writes: {}", err);
+                failing = true;
+            }
+            Ok(Err(_)) => {}
+            Err(err) => error!("Data file check failed: {}", err),
+        }
+    }
+}
+
+/// Syncs `db` for the writes queued on `commit` as soon as enough of them
+/// are waiting or `window` has passed, and wakes them all with the outcome.
+async fn group_commit(db: Arc<Mutex<AKVMEM>>, commit: Arc<GroupCommit>, window: Duration) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(window) => {}
+            _ = commit.full.notified() => {}
+        }
+        let batch = std::mem::take(&mut *commit.waiting.lock().unwrap_or_else(PoisonError::into_inner));
+        if batch.is_empty() {
+            continue;
+        }
+        let db = Arc::clone(&db);
+        let synced = tokio::task::spawn_blocking(move || {
+            db.lock().unwrap_or_else(PoisonError::into_inner).sync_data()
+        }).await;
+        let result = match synced {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match &result {
+            Ok(()) => debug!(writes = batch.len(), "group commit synced"),
+            Err(err) => error!("Group commit fsync failed: {}", err),
+        }
+        for waiter in batch {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+/// Serves one client as `settings` say. With `group_commit` set, writes are
+/// acknowledged once they have been synced.
+async fn process<S>(
+    socket: S,
+    databases: Databases,
+    stats: Arc<Stats>,
+    pubsub: Arc<PubSub>,
+    group_commit: Option<GroupCommits>,
+    settings: ServerSettings,
+)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     use libactionkv::server_helpers::{Command, Connection, Frame};
 
-    let mut connection = Connection{ stream: socket, buf: BytesMut::with_capacity(4096)};
-
-    while let Ok(Some(frame)) = connection.read_frame().await {
-        let cmd = Command::from_frame(&frame).unwrap();
-        let res = match db.lock().as_mut() {
-            Ok(db) => {
-                match cmd {
-                    Command::Set{key, value} => {
-                        match db.insert(&key.as_bytes().to_vec(), &value.as_bytes().to_vec()) {
-                            Ok(()) => Frame::Simple("OK".to_string()),
-                            Err(err) => Frame::Error(err.to_string())
-                        }
+    let mut connection = Connection::new(socket);
+    let _client = ClientGuard::new(&stats.connected_clients);
+    let ServerSettings { requirepass, limits, noop_commands } = settings;
+    if limits.maxclients.is_some_and(|max| stats.connected_clients.load(Ordering::Relaxed) > max) {
+        warn!("Refusing connection: max number of clients reached");
+        let _ = connection.write_frame(Frame::Error("ERR max number of clients reached".to_string())).await;
+        return;
+    }
+
+    let mut authenticated = requirepass.is_none();
+    let mut selected = 0;
+    let (mut subscriptions, mut messages) = Subscriptions::new();
+    debug!("client connected");
+    loop {
+        // Wait for the client's next command, forwarding messages from
+        // subscribed channels in the meantime. read_frame keeps whatever it
+        // has buffered when a message wins the race.
+        let timeout = limits.timeout.filter(|_| subscriptions.forwarders.is_empty());
+        let idle = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let read = tokio::select! {
+            read = connection.read_frame() => read,
+            _ = idle => {
+                debug!("Closing idle connection");
+                break;
+            }
+            Some((channel, message)) = messages.recv() => {
+                let push = Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("message")),
+                    Frame::Bulk(Bytes::from(channel)),
+                    Frame::Bulk(message),
+                ]);
+                if let Err(err) = connection.write_frame(push).await {
+                    debug!("Unable to send message: {}", err);
+                    break;
+                }
+                continue;
+            }
+        };
+        let frame = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                // There is no telling where the next frame starts, so reply
+                // like redis does and hang up.
+                warn!("Closing connection: {}", err);
+                let _ = connection.write_frame(Frame::Error(format!("ERR {}", err))).await;
+                break;
+            }
+            Err(err) => {
+                debug!("Closing connection: {}", err);
+                break;
+            }
+        };
+        if is_noop(&frame, &noop_commands) {
+            if let Err(err) = connection.write_frame(Frame::Simple("OK".to_string())).await {
+                debug!("Unable to reply: {}", err);
+                break;
+            }
+            continue;
+        }
+        let cmd = match Command::from_frame(&frame) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                warn!("Rejected command: {}", err);
+                if let Err(err) = connection.write_frame(Frame::error(err)).await {
+                    debug!("Unable to reply: {}", err);
+                    break;
+                }
+                continue;
+            }
+        };
+        stats.total_commands.fetch_add(1, Ordering::Relaxed);
+        let reply = match &cmd {
+            Command::Auth { password } => Some(match &requirepass {
+                Some(expected) if password.as_str() == &**expected => {
+                    authenticated = true;
+                    Frame::Simple("OK".to_string())
+                }
+                Some(_) => {
+                    warn!("Rejected AUTH with a wrong password");
+                    Frame::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string())
+                }
+                None => Frame::Error("ERR AUTH called without any password configured".to_string()),
+            }),
+            Command::Quit => None,
+            _ if !authenticated => Some(Frame::Error("NOAUTH Authentication required.".to_string())),
+            Command::Select { index } => Some(if *index < databases.databases() {
+                selected = *index;
+                Frame::Simple("OK".to_string())
+            } else {
+                Frame::Error("ERR DB index is out of range".to_string())
+            }),
+            Command::CommandInfo { subcommand } => Some(command_info(subcommand.as_deref())),
+            Command::Client { subcommand, args } => Some(match (subcommand.as_str(), &args[..]) {
+                ("getname", []) => Frame::Bulk(Bytes::from(connection.name().unwrap_or_default().to_string())),
+                ("setname", [name]) if name.chars().any(|c| !c.is_ascii_graphic()) => Frame::Error(
+                    "ERR Client names cannot contain spaces, newlines or special characters.".to_string(),
+                ),
+                ("setname", [name]) => {
+                    // An empty name clears it.
+                    connection.set_name(Some(name.clone()).filter(|name| !name.is_empty()));
+                    Frame::Simple("OK".to_string())
+                }
+                ("getname", _) | ("setname", _) => {
+                    Frame::Error(format!("ERR wrong number of arguments for 'client|{}' command", subcommand))
+                }
+                _ => Frame::Error(format!("ERR unknown subcommand '{}'", subcommand)),
+            }),
+            // There are no replicas to wait for.
+            Command::Wait { .. } => Some(Frame::Integer(0)),
+            Command::Publish { channel, message } => {
+                Some(Frame::Integer(pubsub.publish(channel, Bytes::from(message.clone())) as i64))
+            }
+            Command::Copy { src, dst, .. } if databases.slot(selected, src.as_bytes()) != databases.slot(selected, dst.as_bytes()) => {
+                Some(Frame::Error("CROSSSLOT Keys in request don't hash to the same slot".to_string()))
+            }
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            if let Err(err) = connection.write_frame(reply).await {
+                debug!("Unable to reply: {}", err);
+                break;
+            }
+            continue;
+        }
+        if let Command::RandomKey | Command::Touch { .. } | Command::Exists { .. } | Command::Unlink { .. }
+            | Command::Info | Command::Stats | Command::Verify | Command::Export | Command::Import { .. } = cmd
+        {
+            if let Command::Unlink { keys } = &cmd {
+                Stats::count(&stats.deletes, keys.len() as u64);
+            }
+            // Off the runtime thread like single-key commands below; these
+            // can read every record of the database.
+            let shards = databases.shards(selected).to_vec();
+            let shared_stats = Arc::clone(&stats);
+            let reply = match tokio::task::spawn_blocking(move || fan_out(&shards, &cmd, &shared_stats)).await {
+                Ok(reply) => reply.unwrap_or_else(Frame::error),
+                Err(err) => Frame::error(err),
+            };
+            if let Err(err) = connection.write_frame(reply).await {
+                debug!("Unable to reply: {}", err);
+                break;
+            }
+            continue;
+        }
+        if let Command::Subscribe { channels } = &cmd {
+            // One confirmation per channel, each with the running count.
+            for channel in channels {
+                let count = subscriptions.add(&pubsub, channel);
+                let confirm = Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("subscribe")),
+                    Frame::Bulk(Bytes::from(channel.clone())),
+                    Frame::Integer(count as i64),
+                ]);
+                if let Err(err) = connection.write_frame(confirm).await {
+                    debug!("Unable to reply: {}", err);
+                    return;
+                }
+            }
+            continue;
+        }
+        if let Command::Debug { subcommand, args } = &cmd {
+            // A sleep is awaited here, with no lock taken, so a sleeping
+            // client holds up neither the store nor the runtime.
+            let reply = match subcommand.as_str() {
+                "sleep" => match args
+                    .first()
+                    .and_then(|seconds| seconds.parse().ok())
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                {
+                    Some(duration) => {
+                        tokio::time::sleep(duration).await;
+                        Frame::Simple("OK".to_string())
                     }
-                    Command::Get{ key } => {
-                        let val = db.get(&key.as_bytes().to_vec());
-                        match  val {
-                            Ok(Some(val)) => {
-                                Frame::Bulk(Bytes::from(val))
-                            }
-                            Ok(None) => Frame::Null,
-                            Err(err) => Frame::Error(err.to_string())
-                        }
+                    None => Frame::Error("ERR value is not a valid float".to_string()),
+                },
+                // Every database, as after a restart. A store whose log
+                // doesn't load keeps the index it had. Replaying the logs
+                // runs off the runtime thread, as fan_out does.
+                "reload" => {
+                    let stores = databases.stores.clone();
+                    let reload = move || {
+                        stores.iter().try_for_each(|db| db.lock().unwrap_or_else(PoisonError::into_inner).reload())
+                    };
+                    match tokio::task::spawn_blocking(reload).await {
+                        Ok(Ok(())) => Frame::Simple("OK".to_string()),
+                        Ok(Err(err)) => Frame::error(err),
+                        Err(err) => Frame::error(err),
                     }
-                    Command::Delete{key} => {
-                        match db.delete(&key.as_bytes().to_vec()) {
-                            Ok(()) => Frame::Simple("OK".to_string()),
-                            Err(err) => Frame::Error(err.to_string())
-                        }
-                        
+                }
+                other => Frame::Error(format!("ERR unknown subcommand '{}'", other)),
+            };
+            if let Err(err) = connection.write_frame(reply).await {
+                debug!("Unable to reply: {}", err);
+                break;
+            }
+            continue;
+        }
+        if let Command::BlPop { keys, timeout } = &cmd {
+            // Like DEBUG SLEEP, the wait happens with no lock held.
+            let reply = blpop(&databases, selected, &pubsub, group_commit.as_ref(), keys, *timeout).await;
+            if let Err(err) = connection.write_frame(reply).await {
+                debug!("Unable to reply: {}", err);
+                break;
+            }
+            continue;
+        }
+        if let Command::Quit = cmd {
+            // write_frame flushes, so the OK is out before the socket closes.
+            if let Err(err) = connection.write_frame(Frame::Simple("OK".to_string())).await {
+                debug!("Unable to reply: {}", err);
+            }
+            break;
+        }
+        let started = Instant::now();
+        let name = cmd.name();
+        let key = cmd.key();
+        match &cmd {
+            Command::Get { .. } => Stats::count(&stats.gets, 1),
+            Command::Set { .. } | Command::Update { .. } | Command::SetEx { .. } => {
+                Stats::count(&stats.sets, 1);
+                let len = key.as_ref().map_or(0, String::len) + cmd.value().as_ref().map_or(0, String::len);
+                Stats::count(&stats.bytes_written, len as u64);
+            }
+            Command::Delete { .. } => Stats::count(&stats.deletes, 1),
+            _ => {}
+        }
+        let event = keyspace_event(&cmd);
+        // Everything left names the one key it works on, COPY the key it
+        // writes, which shares a shard with the one it reads.
+        let slot = databases.slot(selected, key.as_ref().map_or(&[][..], |key| key.as_bytes()));
+        // The store does blocking file I/O, so the command runs on the
+        // blocking thread pool, lock and all, and this task only awaits the
+        // reply. A slow disk then holds up the commands waiting on that
+        // shard rather than every connection on this worker thread. The
+        // reply is written out after the lock is released; a client that is
+        // slow to read its replies only holds up itself.
+        let store = Arc::clone(&databases.stores[slot]);
+        let shared_stats = Arc::clone(&stats);
+        let (res, changed) = tokio::task::spawn_blocking(move || {
+            // A panic in another command poisons the lock, but the store
+            // itself is still usable, so take the guard back instead of
+            // failing every request from now on.
+            let mut db = store.lock().unwrap_or_else(PoisonError::into_inner);
+            execute(&mut db, cmd, &shared_stats)
+        })
+        .await
+        .unwrap_or_else(|err| (Frame::error(err), None));
+        if let (Some(event), Some(key)) = (event, &key) {
+            if changed.unwrap_or_else(|| changed_keyspace(event, &res)) {
+                pubsub.notify_keyspace(selected, key, event);
+                if event == "lpush" || event == "rpush" {
+                    pubsub.wake_list_waiters(selected, key);
+                }
+            }
+        }
+        let res = match &group_commit {
+            // The write went out under the lock above, so the sync this waits
+            // for starts after it.
+            Some(commits) if event.is_some() && !matches!(res, Frame::Error(_)) => {
+                match commits[slot].wait().await {
+                    Ok(Ok(())) => res,
+                    Ok(Err(err)) => Frame::Error(format!("ERR fsync failed: {}", err)),
+                    Err(_) => Frame::Error("ERR fsync failed".to_string()),
+                }
+            }
+            _ => res,
+        };
+        let elapsed = started.elapsed();
+        stats.record_latency(name, elapsed);
+        debug!(command = name, key = ?key, elapsed_us = elapsed.as_micros() as u64, "handled command");
+        if let Err(err) = connection.write_frame(res).await {
+            debug!("Unable to reply: {}", err);
+            break;
+        }
+    }
+    debug!("client disconnected");
+}
+
+/// Runs a command that works on a single shard against its store. Returns
+/// the reply and, for commands whose reply doesn't tell, whether they wrote.
+fn execute(
+    db: &mut AKVMEM,
+    cmd: libactionkv::server_helpers::Command,
+    stats: &Stats,
+) -> (libactionkv::server_helpers::Frame, Option<bool>) {
+    use libactionkv::server_helpers::{Command, Frame};
+
+    // Set by commands whose reply doesn't tell whether they wrote.
+    let mut changed = None;
+    let res = match cmd {
+        Command::Set{key, value, options} => {
+            match db.set_with_options(&key.as_bytes().to_vec(), &value.as_bytes().to_vec(), &options) {
+                Ok((written, previous)) if options.get => {
+                    changed = Some(written);
+                    previous.map_or(Frame::Null, |previous| Frame::Bulk(Bytes::from(previous)))
+                }
+                Ok((true, _)) => Frame::Simple("OK".to_string()),
+                Ok((false, _)) => Frame::Null,
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Get{ key } => {
+            let val = db.get_typed(&key.as_bytes().to_vec());
+            match  val {
+                Ok(Some((ValueType::String, val))) => {
+                    Stats::count(&stats.hits, 1);
+                    Frame::Bulk(val)
+                }
+                Ok(Some(_)) => Frame::Error(WRONG_TYPE.to_string()),
+                Ok(None) => {
+                    Stats::count(&stats.misses, 1);
+                    Frame::Null
+                }
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::GetDel{key} => {
+            match db.get_del(&key.as_bytes().to_vec()) {
+                Ok(Some(val)) => Frame::Bulk(Bytes::from(val)),
+                Ok(None) => Frame::Null,
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Delete{key} => {
+            match db.delete(&key.as_bytes().to_vec()) {
+                Ok(()) => Frame::Simple("OK".to_string()),
+                Err(err) => Frame::error(err)
+            }
+        
+        }
+        Command::Update{key, value} => {
+            match db.update(&key.as_bytes().to_vec(), &value.as_bytes().to_vec()) {
+                Ok(_) => Frame::Simple("OK".to_string()),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::SetEx{key, seconds, value} => {
+            match expires_in(seconds) {
+                Some(expires_at) => {
+                    match db.insert_with_expiry(&key.as_bytes().to_vec(), &value.as_bytes().to_vec(), Some(expires_at)) {
+                        Ok(_) => Frame::Simple("OK".to_string()),
+                        Err(err) => Frame::error(err)
                     }
-                    Command::Update{key, value} => {
-                        match db.update(&key.as_bytes().to_vec(), &value.as_bytes().to_vec()) {
-                            Ok(()) => Frame::Simple("OK".to_string()),
-                            Err(err) => Frame::Error(err.to_string())
+                }
+                None => Frame::error("invalid expire time in 'setex' command")
+            }
+        }
+        Command::Dump{key} => {
+            match db.dump(&key.as_bytes().to_vec()) {
+                Ok(Some(payload)) => Frame::Bulk(Bytes::from(payload)),
+                Ok(None) => Frame::Null,
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Restore{key, serialized_value, replace} => {
+            match db.restore(&key.as_bytes().to_vec(), &serialized_value, replace) {
+                Ok(()) => Frame::Simple("OK".to_string()),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::GetRange{key, start, end} => {
+            match get_range(db, &key.as_bytes().to_vec(), start, end) {
+                Ok(val) => Frame::Bulk(Bytes::from(val)),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::SetRange{key, offset, value} => {
+            match db.set_range(&key.as_bytes().to_vec(), offset, &value.as_bytes().to_vec()) {
+                Ok(len) => Frame::Integer(len as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::HSet{key, field, value} => {
+            match db.hset(&key.as_bytes().to_vec(), &field.as_bytes().to_vec(), &value.as_bytes().to_vec()) {
+                Ok(added) => Frame::Integer(added as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::HGet{key, field} => {
+            match db.hget(&key.as_bytes().to_vec(), &field.as_bytes().to_vec()) {
+                Ok(Some(val)) => Frame::Bulk(Bytes::from(val)),
+                Ok(None) => Frame::Null,
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::HGetAll{key} => {
+            match db.hgetall(&key.as_bytes().to_vec()) {
+                Ok(pairs) => Frame::Array(pairs.into_iter().flat_map(|(field, val)| {
+                    vec![Frame::Bulk(Bytes::from(field)), Frame::Bulk(Bytes::from(val))]
+                }).collect()),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::HScan{key, cursor, count} => {
+            match db.hscan(&key.as_bytes().to_vec(), cursor, count) {
+                Ok((next, pairs)) => Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(next.to_string())),
+                    Frame::Array(pairs.into_iter().flat_map(|(field, val)| {
+                        vec![Frame::Bulk(Bytes::from(field)), Frame::Bulk(Bytes::from(val))]
+                    }).collect()),
+                ]),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::HDel{key, field} => {
+            match db.hdel(&key.as_bytes().to_vec(), &field.as_bytes().to_vec()) {
+                Ok(removed) => Frame::Integer(removed as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::LPush{key, values} => {
+            let values: Vec<_> = values.into_iter().map(String::into_bytes).collect();
+            match db.lpush(&key.as_bytes().to_vec(), &values) {
+                Ok(len) => Frame::Integer(len as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::RPush{key, values} => {
+            let values: Vec<_> = values.into_iter().map(String::into_bytes).collect();
+            match db.rpush(&key.as_bytes().to_vec(), &values) {
+                Ok(len) => Frame::Integer(len as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::LRange{key, start, stop} => {
+            match db.lrange(&key.as_bytes().to_vec(), start, stop) {
+                Ok(items) => Frame::Array(items.into_iter().map(|item| Frame::Bulk(Bytes::from(item))).collect()),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::LLen{key} => {
+            match db.llen(&key.as_bytes().to_vec()) {
+                Ok(len) => Frame::Integer(len as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Expire{key, seconds} => {
+            match expires_in(seconds) {
+                Some(expires_at) => match db.set_expiry(&key.as_bytes().to_vec(), Some(expires_at)) {
+                    Ok(found) => Frame::Integer(found as i64),
+                    Err(err) => Frame::error(err)
+                },
+                None => Frame::error("invalid expire time in 'expire' command")
+            }
+        }
+        Command::PExpire{key, millis} => {
+            match millis.checked_add(libactionkv::unix_millis()) {
+                Some(expires_at) => match db.set_expiry(&key.as_bytes().to_vec(), Some(expires_at)) {
+                    Ok(found) => Frame::Integer(found as i64),
+                    Err(err) => Frame::error(err)
+                },
+                None => Frame::error("invalid expire time in 'pexpire' command")
+            }
+        }
+        Command::PTtl{key} => {
+            // -2 for a missing key and -1 for one that never expires.
+            match db.expiry(&key.as_bytes().to_vec()) {
+                Ok(Some(Some(expires_at))) => Frame::Integer(expires_at.saturating_sub(libactionkv::unix_millis()) as i64),
+                Ok(Some(None)) => Frame::Integer(-1),
+                Ok(None) => Frame::Integer(-2),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::ExpireAt{key, unix_ts} => {
+            match unix_ts.checked_mul(1000) {
+                Some(expires_at) => match db.set_expiry(&key.as_bytes().to_vec(), Some(expires_at)) {
+                    Ok(found) => Frame::Integer(found as i64),
+                    Err(err) => Frame::error(err)
+                },
+                None => Frame::error("invalid expire time in 'expireat' command")
+            }
+        }
+        Command::Persist{key} => {
+            match db.persist(&key.as_bytes().to_vec()) {
+                Ok(removed) => Frame::Integer(removed as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Quit | Command::Auth { .. } | Command::Select { .. } | Command::CommandInfo { .. } | Command::Wait { .. }
+        | Command::Subscribe { .. } | Command::Publish { .. } | Command::Debug { .. } | Command::Client { .. } | Command::BlPop { .. }
+        | Command::RandomKey | Command::Touch { .. } | Command::Export | Command::Import { .. }
+        | Command::Exists { .. } | Command::Unlink { .. } | Command::Info | Command::Stats | Command::Verify => {
+            unreachable!("answered before taking the lock")
+        }
+        Command::IncrBy{key, delta} => {
+            match db.incr_by(&key.as_bytes().to_vec(), delta) {
+                Ok(value) => Frame::Integer(value),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::DecrBy{key, delta} => {
+            match delta.checked_neg().map(|delta| db.incr_by(&key.as_bytes().to_vec(), delta)) {
+                Some(Ok(value)) => Frame::Integer(value),
+                Some(Err(err)) => Frame::error(err),
+                None => Frame::error("decrement would overflow")
+            }
+        }
+        Command::IncrByFloat{key, delta} => {
+            match db.incr_by_float(&key.as_bytes().to_vec(), delta) {
+                Ok(value) => Frame::Bulk(Bytes::from(value)),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Cas{key, expected, new} => {
+            match db.compare_and_swap(&key.as_bytes().to_vec(), &expected.as_bytes().to_vec(), &new.as_bytes().to_vec()) {
+                Ok(swapped) => Frame::Integer(swapped as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Copy{src, dst, replace} => {
+            match db.copy(&src.as_bytes().to_vec(), &dst.as_bytes().to_vec(), replace) {
+                Ok(copied) => Frame::Integer(copied as i64),
+                Err(err) => Frame::error(err)
+            }
+        }
+        Command::Object{subcommand, key} => {
+            let key = key.as_bytes().to_vec();
+            match subcommand.as_str() {
+                "encoding" => match db.get_typed(&key) {
+                    Ok(Some((value_type, _))) => Frame::Bulk(Bytes::from(encoding(value_type))),
+                    Ok(None) => Frame::Error("ERR no such key".to_string()),
+                    Err(err) => Frame::error(err)
+                },
+                "idletime" => match db.idle_time(&key) {
+                    Ok(Some(idle)) => Frame::Integer((idle / 1000) as i64),
+                    Ok(None) => Frame::Error("ERR no such key".to_string()),
+                    Err(err) => Frame::error(err)
+                },
+                "timestamp" => match db.written_at(&key) {
+                    Ok(Some(written_at)) => Frame::Integer((written_at / 1000) as i64),
+                    Ok(None) => Frame::Error("ERR no such key".to_string()),
+                    Err(err) => Frame::error(err)
+                },
+                _ => Frame::Error(format!("ERR unknown subcommand '{}'", subcommand))
+            }
+        }
+    };
+    (res, changed)
+}
+
+/// BLPOP: pops the head of the first of `keys` holding a non-empty list and
+/// replies with the key and the element. If they are all empty it waits for
+/// a push to any of them, up to `timeout` seconds or forever if that is 0,
+/// and replies with a null array if none comes.
+async fn blpop(
+    databases: &Keyspace,
+    selected: usize,
+    pubsub: &PubSub,
+    group_commit: Option<&GroupCommits>,
+    keys: &[String],
+    timeout: f64,
+) -> libactionkv::server_helpers::Frame {
+    use libactionkv::server_helpers::Frame;
+
+    let deadline = match timeout {
+        timeout if timeout > 0.0 => match Duration::try_from_secs_f64(timeout) {
+            Ok(timeout) => Some(tokio::time::Instant::now() + timeout),
+            Err(_) => None,
+        },
+        _ => None,
+    };
+    // Subscribed before the lists are first looked at, so a push that lands
+    // in between is still heard.
+    let mut waiters: Vec<_> = keys.iter().map(|key| pubsub.list_waiter(selected, key)).collect();
+    let reply = 'wait: loop {
+        for key in keys {
+            let slot = databases.slot(selected, key.as_bytes());
+            // On the blocking pool like any other store call.
+            let (store, list) = (Arc::clone(&databases.stores[slot]), key.as_bytes().to_vec());
+            let popped = tokio::task::spawn_blocking(move || {
+                store.lock().unwrap_or_else(PoisonError::into_inner).lpop(&list)
+            }).await.unwrap_or_else(|err| Err(err.into()));
+            match popped {
+                Ok(Some(value)) => {
+                    pubsub.notify_keyspace(selected, key, "lpop");
+                    if let Some(commits) = group_commit {
+                        match commits[slot].wait().await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => break 'wait Frame::Error(format!("ERR fsync failed: {}", err)),
+                            Err(_) => break 'wait Frame::Error("ERR fsync failed".to_string()),
                         }
                     }
+                    break 'wait Frame::Array(vec![Frame::Bulk(Bytes::from(key.clone())), Frame::Bulk(Bytes::from(value))]);
                 }
+                Ok(None) => {}
+                Err(err) => break 'wait Frame::error(err),
             }
-            Err(err) => Frame::Error(err.to_string())
-        };
-        connection.write_frame(res).await.unwrap();
-        
+        }
+        // A lagged receiver has missed pushes, which is as good as hearing
+        // one.
+        let any_push = futures::future::select_all(waiters.iter_mut().map(|waiter| Box::pin(waiter.recv())));
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, any_push).await.is_err() {
+                    break Frame::NullArray;
+                }
+            }
+            None => {
+                let _ = any_push.await;
+            }
+        }
+    };
+    for (key, waiter) in keys.iter().zip(waiters) {
+        pubsub.release_list_waiter(selected, key, waiter);
     }
+    reply
 }
 
+/// Whether `frame` is a command on the `noop_commands` list, either by its
+/// name alone or by its name and subcommand.
+fn is_noop(frame: &libactionkv::server_helpers::Frame, noop_commands: &[String]) -> bool {
+    use libactionkv::server_helpers::Frame;
+
+    let args = match frame {
+        Frame::Array(args) if !noop_commands.is_empty() => args,
+        _ => return false,
+    };
+    let word = |index: usize| match args.get(index) {
+        Some(Frame::Bulk(word)) => Some(String::from_utf8_lossy(word).to_lowercase()),
+        _ => None,
+    };
+    let name = match word(0) {
+        Some(name) => name,
+        None => return false,
+    };
+    let with_subcommand = word(1).map(|subcommand| format!("{} {}", name, subcommand));
+    noop_commands.iter().any(|noop| *noop == name || Some(noop) == with_subcommand.as_ref())
+}
 
+/// Answers the commands that span every key of a database by going through
+/// its shards one lock at a time. Multi-key commands are not atomic across
+/// shards.
+fn fan_out(
+    shards: &[Arc<Mutex<AKVMEM>>],
+    cmd: &libactionkv::server_helpers::Command,
+    stats: &Stats,
+) -> std::io::Result<libactionkv::server_helpers::Frame> {
+    use libactionkv::server_helpers::{Command, Frame};
 
+    let reply = match cmd {
+        Command::RandomKey => {
+            // Starting at a random shard gives every shard's keys a chance.
+            let start = rand::thread_rng().gen_range(0..shards.len());
+            for shard in shards.iter().cycle().skip(start).take(shards.len()) {
+                if let Some(key) = shard.lock().unwrap_or_else(PoisonError::into_inner).random_key()? {
+                    return Ok(Frame::Bulk(Bytes::from(key)))
+                }
+            }
+            Frame::Null
+        }
+        Command::Export => {
+            // One export of the whole database, whichever shard a key is in.
+            let mut exporter = Exporter::new(Vec::new())?;
+            for shard in shards {
+                exporter.add(&*shard.lock().unwrap_or_else(PoisonError::into_inner))?;
+            }
+            Frame::Bulk(Bytes::from(exporter.finish()?))
+        }
+        Command::Import { payload } => {
+            let mut imported = 0;
+            for kv in ExportReader::new(&payload[..])? {
+                let kv = kv?;
+                let shard = &shards[shard_of(kv.key(), shards.len())];
+                if shard.lock().unwrap_or_else(PoisonError::into_inner).import_pair(&kv)? {
+                    imported += 1;
+                }
+            }
+            Frame::Integer(imported)
+        }
+        Command::Touch { keys } => Frame::Integer(count_keys(shards, keys, |db, key| {
+            Ok(db.touch_keys(std::slice::from_ref(key))? > 0)
+        })?),
+        Command::Exists { keys } => Frame::Integer(count_keys(shards, keys, |db, key| db.exists(key))?),
+        Command::Unlink { keys } => {
+            let mut by_shard = vec![Vec::new(); shards.len()];
+            for key in keys {
+                by_shard[shard_of(key.as_bytes(), shards.len())].push(key.as_bytes().to_vec());
+            }
+            let mut removed = 0;
+            for (shard, keys) in shards.iter().zip(by_shard).filter(|(_, keys)| !keys.is_empty()) {
+                removed += shard.lock().unwrap_or_else(PoisonError::into_inner).unlink(&keys)?;
+                // The keys are already gone from the index, so the reply doesn't
+                // wait for their tombstones. Any write or sync in the meantime
+                // writes them first.
+                let db = Arc::clone(shard);
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = db.lock().unwrap_or_else(PoisonError::into_inner).write_unlinked() {
+                        error!("Unable to write unlinked tombstones: {}", err);
+                    }
+                });
+            }
+            Frame::Integer(removed as i64)
+        }
+        Command::Info => {
+            let (mut keys, mut file_size, mut used_memory) = (0, 0, 0);
+            for shard in shards {
+                let db = shard.lock().unwrap_or_else(PoisonError::into_inner);
+                keys += db.index.len();
+                file_size += db.file_size()?;
+                used_memory += db.used_memory().unwrap_or(0);
+            }
+            let path = shards[0].lock().unwrap_or_else(PoisonError::into_inner).path().to_path_buf();
+            Frame::Bulk(Bytes::from(info(&path, keys, file_size, used_memory, stats)))
+        }
+        Command::Verify => {
+            let (mut valid, mut corrupt, mut corrupt_bytes, mut offsets) = (0, 0, 0, Vec::new());
+            for (index, shard) in shards.iter().enumerate() {
+                let report = shard.lock().unwrap_or_else(PoisonError::into_inner).verify()?;
+                valid += report.valid_records;
+                corrupt += report.corrupt_offsets.len();
+                corrupt_bytes += report.corrupt_bytes;
+                // Offsets are only unique within a file, so say whose they are.
+                offsets.extend(report.corrupt_offsets.iter().map(|offset| {
+                    if shards.len() == 1 { offset.to_string() } else { format!("{}:{}", index, offset) }
+                }));
+            }
+            Frame::Bulk(Bytes::from(format!(
+                "valid_records:{}\r\ncorrupt_records:{}\r\ncorrupt_bytes:{}\r\ncorrupt_offsets:{}\r\n",
+                valid,
+                corrupt,
+                corrupt_bytes,
+                offsets.join(","),
+            )))
+        }
+        Command::Stats => {
+            // Only counters the stores keep anyway, so this stays cheap
+            // however many keys there are.
+            let (mut keys, mut file_size, mut dead_bytes, mut records) = (0, 0, 0, 0);
+            for shard in shards {
+                let db = shard.lock().unwrap_or_else(PoisonError::into_inner);
+                let size = db.file_size()?;
+                keys += db.index.len() as u64;
+                file_size += size;
+                dead_bytes += db.dead_bytes();
+                records += size.saturating_sub(HEADER_LEN);
+            }
+            let dead_ratio = if records == 0 { 0.0 } else { dead_bytes as f64 / records as f64 };
+            Frame::Array(vec![
+                Frame::Integer(keys as i64),
+                Frame::Integer(file_size as i64),
+                Frame::Integer(dead_bytes as i64),
+                Frame::Bulk(Bytes::from(format!("{:.4}", 1.0 - dead_ratio))),
+            ])
+        }
+        _ => unreachable!("only commands that span the keyspace are fanned out"),
+    };
+    Ok(reply)
+}
+
+/// How many of `keys` `found` holds for, each asked of the shard that owns
+/// it. Like redis, a key named more than once counts each time.
+fn count_keys<F>(shards: &[Arc<Mutex<AKVMEM>>], keys: &[String], found: F) -> std::io::Result<i64>
+where
+    F: Fn(&mut AKVMEM, &Vec<u8>) -> std::io::Result<bool>,
+{
+    let mut count = 0;
+    for key in keys {
+        let key = key.as_bytes().to_vec();
+        let mut db = shards[shard_of(&key, shards.len())].lock().unwrap_or_else(PoisonError::into_inner);
+        if found(&mut db, &key)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn info(path: &Path, keys: usize, file_size: u64, used_memory: u64, stats: &Stats) -> String {
+    format!(
+        "keys:{}\r\ndata_file:{}\r\ndata_file_size:{}\r\nused_memory:{}\r\nuptime_in_seconds:{}\r\nconnected_clients:{}\r\ntotal_commands_processed:{}\r\n\
+         total_gets:{}\r\ntotal_sets:{}\r\ntotal_deletes:{}\r\nkeyspace_hits:{}\r\nkeyspace_misses:{}\r\ntotal_bytes_written:{}\r\n",
+        keys,
+        path.display(),
+        file_size,
+        used_memory,
+        stats.started.elapsed().as_secs(),
+        stats.connected_clients.load(Ordering::Relaxed),
+        stats.total_commands.load(Ordering::Relaxed),
+        stats.gets.load(Ordering::Relaxed),
+        stats.sets.load(Ordering::Relaxed),
+        stats.deletes.load(Ordering::Relaxed),
+        stats.hits.load(Ordering::Relaxed),
+        stats.misses.load(Ordering::Relaxed),
+        stats.bytes_written.load(Ordering::Relaxed),
+    ) + &latency_section(stats)
+}
+
+/// p50, p99 and p99.9 in microseconds for each command that has run, in the
+/// format redis uses for its latencystats.
+fn latency_section(stats: &Stats) -> String {
+    let mut out = "# Latency\r\n".to_string();
+    for &name in COMMAND_NAMES {
+        let histogram = &stats.latency[name];
+        if let (Some(p50), Some(p99), Some(p999)) =
+            (histogram.percentile(50.0), histogram.percentile(99.0), histogram.percentile(99.9))
+        {
+            out += &format!("latency_percentiles_usec_{}:p50={},p99={},p99.9={}\r\n", name, p50, p99, p999);
+        }
+    }
+    out
+}
+
+/// The counters in Prometheus text exposition format.
+fn metrics(keys: usize, stats: &Stats) -> String {
+    let counters = [
+        ("akv_commands_total", "Commands processed.", &stats.total_commands),
+        ("akv_gets_total", "GET commands.", &stats.gets),
+        ("akv_sets_total", "SET, UPDATE and SETEX commands.", &stats.sets),
+        ("akv_deletes_total", "DELETE commands.", &stats.deletes),
+        ("akv_hits_total", "GETs that found a value.", &stats.hits),
+        ("akv_misses_total", "GETs of a missing key.", &stats.misses),
+        ("akv_written_bytes_total", "Key and value bytes written.", &stats.bytes_written),
+    ];
+    let mut out = String::new();
+    for (name, help, counter) in counters.iter() {
+        out += &format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, counter.load(Ordering::Relaxed));
+    }
+    out += &format!(
+        "# HELP akv_connected_clients Open client connections.\n# TYPE akv_connected_clients gauge\nakv_connected_clients {}\n",
+        stats.connected_clients.load(Ordering::Relaxed),
+    );
+    out += &format!("# HELP akv_keys Live keys.\n# TYPE akv_keys gauge\nakv_keys {}\n", keys);
+    out
+}
+
+/// Unix time in milliseconds `seconds` from now, or None if that overflows.
+fn expires_in(seconds: u64) -> Option<u64> {
+    seconds.checked_mul(1000)?.checked_add(libactionkv::unix_millis())
+}
+
+/// COMMAND introspection. Plain COMMAND and COMMAND LIST name the supported
+/// commands, COMMAND COUNT counts them and COMMAND DOCS has no docs to give,
+/// which is enough for clients that probe it on connect.
+fn command_info(subcommand: Option<&str>) -> libactionkv::server_helpers::Frame {
+    use libactionkv::server_helpers::Frame;
+
+    match subcommand {
+        None | Some("list") => Frame::Array(COMMAND_NAMES.iter().map(|name| Frame::Bulk(Bytes::from(*name))).collect()),
+        Some("count") => Frame::Integer(COMMAND_NAMES.len() as i64),
+        Some("docs") => Frame::Array(vec![]),
+        Some(other) => Frame::Error(format!("ERR unknown subcommand '{}'", other)),
+    }
+}
+
+/// The keyspace notification a write sends, named like the events redis
+/// sends.
+fn keyspace_event(cmd: &libactionkv::server_helpers::Command) -> Option<&'static str> {
+    use libactionkv::server_helpers::Command;
+
+    let event = match cmd {
+        Command::Set { .. } | Command::Update { .. } | Command::SetEx { .. } | Command::Cas { .. } => "set",
+        Command::Restore { .. } => "restore",
+        Command::SetRange { .. } => "setrange",
+        Command::Copy { .. } => "copy_to",
+        Command::Delete { .. } | Command::GetDel { .. } => "del",
+        Command::HSet { .. } => "hset",
+        Command::HDel { .. } => "hdel",
+        Command::LPush { .. } => "lpush",
+        Command::RPush { .. } => "rpush",
+        Command::Expire { .. } | Command::PExpire { .. } | Command::ExpireAt { .. } => "expire",
+        Command::Persist { .. } => "persist",
+        Command::IncrBy { .. } | Command::DecrBy { .. } => "incrby",
+        Command::IncrByFloat { .. } => "incrbyfloat",
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// Whether the write behind `event` changed anything, judging by its reply.
+/// An integer 0 means nothing happened, except for HSET where it means an
+/// existing field was overwritten and INCRBY where it is the new value. A null reply is GETDEL finding no key or
+/// SET held back by NX or XX. SET with GET replies the same either way, so
+/// `process` tracks that one itself.
+fn changed_keyspace(event: &str, reply: &libactionkv::server_helpers::Frame) -> bool {
+    use libactionkv::server_helpers::Frame;
+
+    match reply {
+        Frame::Error(_) | Frame::Null => false,
+        Frame::Integer(0) => event == "hset" || event == "incrby",
+        _ => true,
+    }
+}
+
+/// What OBJECT ENCODING reports for a key, using the names redis gives the
+/// closest encoding so clients that branch on them behave sensibly.
+fn encoding(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::String => "raw",
+        ValueType::Hash => "hashtable",
+        ValueType::List => "quicklist",
+    }
+}
+
+/// GETRANGE: `start` and `end` are inclusive and count from the end of the
+/// value when negative. A missing key reads as an empty value.
+fn get_range(db: &AKVMEM, key: &Vec<u8>, start: i64, end: i64) -> std::io::Result<Vec<u8>> {
+    let len = match db.value_len(key)? {
+        Some(len) => len as i64,
+        None => return Ok(Vec::new()),
+    };
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || len == 0 {
+        return Ok(Vec::new())
+    }
+    Ok(db.get_range(key, start as u64, (end - start + 1) as u64)?.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A data file of its own for each test, removed once it is dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = env::temp_dir().join(format!("akv_mem-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Serves one client over an in-memory stream, with a single database
+    /// in `file` and default settings.
+    fn serve(file: &TempFile) -> tokio::io::DuplexStream {
+        let mut store = libactionkv::open(&file.0).unwrap();
+        store.load().unwrap();
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(store))], shards: 1 });
+        let (client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+        client
+    }
+
+    /// Sends `request` and checks that the reply is exactly `expected`.
+    async fn exchange(client: &mut tokio::io::DuplexStream, request: &[u8], expected: &[u8]) {
+        client.write_all(request).await.unwrap();
+        let mut reply = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut reply)).await.unwrap().unwrap();
+        assert_eq!(String::from_utf8_lossy(&reply), String::from_utf8_lossy(expected));
+    }
+
+    #[test]
+    fn data_files_resolve_against_dir_to_an_absolute_path() {
+        let temp = env::temp_dir().canonicalize().unwrap();
+        let dir = temp.to_str().unwrap();
+        assert_eq!(resolve_file(Some(dir), "data.akv").unwrap(), temp.join("data.akv").to_str().unwrap());
+        assert_eq!(resolve_file(Some(dir), "/srv/data.akv").unwrap(), "/srv/data.akv");
+        assert!(Path::new(&resolve_file(None, "data.akv").unwrap()).is_absolute());
+        assert!(resolve_file(Some(temp.join("akv-missing-dir").to_str().unwrap()), "data.akv").unwrap_err().starts_with("Unable to use --dir"));
+    }
+
+    #[test]
+    fn latency_percentiles_come_from_the_recorded_buckets() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentile(50.0), None);
+        for micros in 1..=1000 {
+            histogram.record(micros);
+        }
+        // 500 lands in [480, 511] and 990 in [960, 1023].
+        assert_eq!(histogram.percentile(50.0), Some(511));
+        assert_eq!(histogram.percentile(99.0), Some(1023));
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(100.0), Some((1 << LATENCY_MAX_EXPONENT) - 1));
+        for micros in [0, 7, 8, 15, 16, 1000, 1 << 20] {
+            let bucket = Histogram::bucket(micros);
+            assert!(micros <= Histogram::bucket_max(bucket));
+            assert!(bucket == 0 || micros > Histogram::bucket_max(bucket - 1));
+        }
+
+        let stats = Stats::new();
+        stats.record_latency("get", Duration::from_micros(3));
+        let section = latency_section(&stats);
+        assert_eq!(section, "# Latency\r\nlatency_percentiles_usec_get:p50=3,p99=3,p99.9=3\r\n");
+    }
+
+    #[tokio::test]
+    async fn a_task_panicking_under_the_lock_does_not_lock_others_out() {
+        let file = TempFile::new("poison");
+        let mut store = libactionkv::open(&file.0).unwrap();
+        store.load().unwrap();
+        let db = Arc::new(Mutex::new(store));
+        let panicking = Arc::clone(&db);
+        let panicked = tokio::task::spawn_blocking(move || {
+            let _db = panicking.lock();
+            panic!("request failed while holding the lock");
+        }).await;
+        assert!(panicked.unwrap_err().is_panic());
+        assert!(db.is_poisoned());
+
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::new(Keyspace { stores: vec![db], shards: 1 }), Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n", b"$5\r\nvalue\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn debug_sleep_does_not_hold_up_other_clients() {
+        let file = TempFile::new("debug-sleep");
+        let mut store = libactionkv::open(&file.0).unwrap();
+        store.load().unwrap();
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(store))], shards: 1 });
+        let stats = Arc::new(Stats::new());
+        let pubsub = Arc::new(PubSub::new(false));
+
+        let (mut sleeper, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::clone(&stats), Arc::clone(&pubsub), None, ServerSettings::default()));
+        sleeper.write_all(b"*3\r\n$5\r\ndebug\r\n$5\r\nsleep\r\n$2\r\n60\r\n").await.unwrap();
+        // Give the sleeper's task time to pick the command up.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, stats, pubsub, None, ServerSettings::default()));
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n", b"+OK\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn a_key_given_500ms_by_pexpire_is_gone_after_it() {
+        let file = TempFile::new("pexpire");
+        let mut client = serve(&file);
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*2\r\n$4\r\npttl\r\n$1\r\nk\r\n", b":-1\r\n").await;
+        exchange(&mut client, b"*3\r\n$7\r\npexpire\r\n$1\r\nk\r\n$3\r\n500\r\n", b":1\r\n").await;
+
+        client.write_all(b"*2\r\n$4\r\npttl\r\n$1\r\nk\r\n").await.unwrap();
+        let mut reply = [0; 16];
+        let n = client.read(&mut reply).await.unwrap();
+        let ttl: i64 = String::from_utf8_lossy(&reply[1..n - 2]).parse().unwrap();
+        assert!(ttl > 0 && ttl <= 500, "{}", ttl);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        exchange(&mut client, b"*2\r\n$3\r\nget\r\n$1\r\nk\r\n", b"$-1\r\n").await;
+        exchange(&mut client, b"*2\r\n$4\r\npttl\r\n$1\r\nk\r\n", b":-2\r\n").await;
+        exchange(&mut client, b"*3\r\n$7\r\npexpire\r\n$1\r\nk\r\n$3\r\n500\r\n", b":0\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn hscan_replies_with_the_next_cursor_and_a_page_of_pairs() {
+        let file = TempFile::new("hscan");
+        let mut client = serve(&file);
+        exchange(&mut client, b"*4\r\n$4\r\nhset\r\n$1\r\nh\r\n$1\r\na\r\n$1\r\n1\r\n", b":1\r\n").await;
+        exchange(&mut client, b"*4\r\n$4\r\nhset\r\n$1\r\nh\r\n$1\r\nb\r\n$1\r\n2\r\n", b":1\r\n").await;
+
+        exchange(&mut client, b"*5\r\n$5\r\nhscan\r\n$1\r\nh\r\n$1\r\n0\r\n$5\r\nCOUNT\r\n$1\r\n1\r\n", b"*2\r\n$1\r\n1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n").await;
+        exchange(&mut client, b"*5\r\n$5\r\nhscan\r\n$1\r\nh\r\n$1\r\n1\r\n$5\r\ncount\r\n$1\r\n1\r\n", b"*2\r\n$1\r\n0\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n").await;
+        exchange(&mut client, b"*3\r\n$5\r\nhscan\r\n$7\r\nmissing\r\n$1\r\n0\r\n", b"*2\r\n$1\r\n0\r\n*0\r\n").await;
+        exchange(&mut client, b"*5\r\n$5\r\nhscan\r\n$1\r\nh\r\n$1\r\n0\r\n$5\r\ncount\r\n$1\r\n0\r\n", b"-ERR syntax error\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn debug_reload_loads_the_index_from_disk_again() {
+        let file = TempFile::new("debug-reload");
+        let mut client = serve(&file);
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$4\r\nkept\r\n$1\r\n1\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$4\r\ngone\r\n$1\r\n2\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*2\r\n$6\r\ndelete\r\n$4\r\ngone\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*2\r\n$5\r\ndebug\r\n$6\r\nRELOAD\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*2\r\n$3\r\nget\r\n$4\r\nkept\r\n", b"$1\r\n1\r\n").await;
+        exchange(&mut client, b"*2\r\n$3\r\nget\r\n$4\r\ngone\r\n", b"$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn a_client_not_reading_its_replies_does_not_hold_up_others() {
+        let file = TempFile::new("slow-reader");
+        let mut store = libactionkv::open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"big".to_vec(), &vec![b'x'; 64 * 1024]).unwrap();
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(store))], shards: 1 });
+        let stats = Arc::new(Stats::new());
+        let pubsub = Arc::new(PubSub::new(false));
+
+        // The pipe holds far less than the reply, so writing it stalls until
+        // the client reads, which it never does.
+        let (mut slow, socket) = tokio::io::duplex(64);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::clone(&stats), Arc::clone(&pubsub), None, ServerSettings::default()));
+        slow.write_all(b"*2\r\n$3\r\nget\r\n$3\r\nbig\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, stats, pubsub, None, ServerSettings::default()));
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n", b"+OK\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn exists_counts_repeated_keys_each_time() {
+        let file = TempFile::new("exists");
+        let mut client = serve(&file);
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n", b"+OK\r\n").await;
+        exchange(
+            &mut client,
+            b"*4\r\n$6\r\nexists\r\n$3\r\nkey\r\n$7\r\nmissing\r\n$3\r\nkey\r\n",
+            b":2\r\n",
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn keys_are_routed_to_their_shard_and_multi_key_commands_fan_out() {
+        let files = [TempFile::new("shard0"), TempFile::new("shard1")];
+        let stores = files.iter().map(|file| {
+            let mut store = libactionkv::open(&file.0).unwrap();
+            store.load().unwrap();
+            Arc::new(Mutex::new(store))
+        }).collect();
+        let databases: Databases = Arc::new(Keyspace { stores, shards: 2 });
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+
+        let keys = ["a", "b", "c", "d", "e", "f"];
+        for key in keys {
+            exchange(&mut client, format!("*3\r\n$3\r\nset\r\n$1\r\n{}\r\n$1\r\nv\r\n", key).as_bytes(), b"+OK\r\n").await;
+        }
+        for (shard, store) in databases.stores.iter().enumerate() {
+            let store = store.lock().unwrap();
+            assert!(store.index.keys().all(|key| shard_of(key, 2) == shard));
+        }
+        assert!(databases.stores.iter().all(|store| !store.lock().unwrap().index.is_empty()));
+        exchange(&mut client, b"*4\r\n$6\r\nexists\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nz\r\n", b":2\r\n").await;
+        exchange(&mut client, b"*7\r\n$6\r\nunlink\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\ne\r\n$1\r\nf\r\n", b":6\r\n").await;
+        exchange(&mut client, b"*1\r\n$9\r\nrandomkey\r\n", b"$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn listed_noop_commands_are_answered_ok() {
+        let file = TempFile::new("noop");
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(libactionkv::open(&file.0).unwrap()))], shards: 1 });
+        let settings = ServerSettings { noop_commands: vec!["client setinfo".to_string()].into(), ..ServerSettings::default() };
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, settings));
+        exchange(&mut client, b"*4\r\n$6\r\nCLIENT\r\n$7\r\nSETINFO\r\n$8\r\nlib-name\r\n$5\r\nredis\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*2\r\n$6\r\nclient\r\n$4\r\nkill\r\n", b"-ERR unknown subcommand 'kill'\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn counters_reply_with_the_new_value() {
+        let file = TempFile::new("incrby");
+        let mut client = serve(&file);
+
+        exchange(&mut client, b"*3\r\n$6\r\nINCRBY\r\n$1\r\nn\r\n$2\r\n10\r\n", b":10\r\n").await;
+        exchange(&mut client, b"*3\r\n$6\r\ndecrby\r\n$1\r\nn\r\n$2\r\n15\r\n", b":-5\r\n").await;
+        exchange(&mut client, b"*2\r\n$3\r\nget\r\n$1\r\nn\r\n", b"$2\r\n-5\r\n").await;
+        exchange(
+            &mut client,
+            b"*3\r\n$6\r\nincrby\r\n$1\r\nn\r\n$3\r\none\r\n",
+            b"-ERR value is not an integer or out of range\r\n",
+        ).await;
+        exchange(
+            &mut client,
+            b"*3\r\n$6\r\ndecrby\r\n$1\r\nn\r\n$20\r\n-9223372036854775808\r\n",
+            b"-ERR decrement would overflow\r\n",
+        ).await;
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$1\r\ns\r\n$3\r\nabc\r\n", b"+OK\r\n").await;
+        exchange(
+            &mut client,
+            b"*3\r\n$6\r\nincrby\r\n$1\r\ns\r\n$1\r\n1\r\n",
+            b"-ERR value is not an integer or out of range\r\n",
+        ).await;
+
+        exchange(&mut client, b"*3\r\n$11\r\nincrbyfloat\r\n$1\r\nn\r\n$4\r\n5.75\r\n", b"$4\r\n0.75\r\n").await;
+        exchange(&mut client, b"*3\r\n$11\r\nINCRBYFLOAT\r\n$1\r\nf\r\n$4\r\n-1e2\r\n", b"$4\r\n-100\r\n").await;
+        exchange(&mut client, b"*3\r\n$11\r\nincrbyfloat\r\n$1\r\nf\r\n$3\r\nnan\r\n", b"-ERR value is not a valid float\r\n").await;
+        exchange(&mut client, b"*3\r\n$11\r\nincrbyfloat\r\n$1\r\ns\r\n$1\r\n1\r\n", b"-ERR value is not a valid float\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn blpop_waits_for_a_push_or_times_out() {
+        let file = TempFile::new("blpop");
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(libactionkv::open(&file.0).unwrap()))], shards: 1 });
+        let pubsub = Arc::new(PubSub::new(false));
+        let (mut blocked, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::new(Stats::new()), Arc::clone(&pubsub), None, ServerSettings::default()));
+        let (mut pusher, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::clone(&pubsub), None, ServerSettings::default()));
+
+        exchange(&mut blocked, b"*3\r\n$5\r\nblpop\r\n$5\r\nempty\r\n$3\r\n0.1\r\n", b"*-1\r\n").await;
+        exchange(&mut pusher, b"*4\r\n$5\r\nrpush\r\n$1\r\nq\r\n$1\r\na\r\n$1\r\nb\r\n", b":2\r\n").await;
+        exchange(&mut blocked, b"*4\r\n$5\r\nBLPOP\r\n$5\r\nempty\r\n$1\r\nq\r\n$1\r\n1\r\n", b"*2\r\n$1\r\nq\r\n$1\r\na\r\n").await;
+        exchange(&mut blocked, b"*3\r\n$5\r\nblpop\r\n$1\r\nq\r\n$1\r\n0\r\n", b"*2\r\n$1\r\nq\r\n$1\r\nb\r\n").await;
+
+        // Nothing left, so this one waits until the push below.
+        blocked.write_all(b"*3\r\n$5\r\nblpop\r\n$1\r\nq\r\n$1\r\n0\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        exchange(&mut pusher, b"*3\r\n$5\r\nlpush\r\n$1\r\nq\r\n$1\r\nc\r\n", b":1\r\n").await;
+        exchange(&mut blocked, b"", b"*2\r\n$1\r\nq\r\n$1\r\nc\r\n").await;
+        exchange(&mut pusher, b"*2\r\n$6\r\nexists\r\n$1\r\nq\r\n", b":0\r\n").await;
+        assert!(pubsub.list_waiters.lock().unwrap().is_empty());
+
+        exchange(&mut pusher, b"*3\r\n$5\r\nblpop\r\n$1\r\nq\r\n$2\r\n-1\r\n", b"-ERR timeout is negative\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn an_export_imports_into_a_sharded_server() {
+        let (src_file, dst_files) = (TempFile::new("export-src"), [TempFile::new("export-dst-0"), TempFile::new("export-dst-1")]);
+        let src = Arc::new(Mutex::new(libactionkv::open(&src_file.0).unwrap()));
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::clone(&src)], shards: 1 });
+        let (mut client, socket) = tokio::io::duplex(1 << 16);
+        tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+        exchange(&mut client, b"*3\r\n$3\r\nset\r\n$1\r\na\r\n$1\r\n1\r\n", b"+OK\r\n").await;
+        exchange(&mut client, b"*3\r\n$5\r\nrpush\r\n$1\r\nl\r\n$1\r\nx\r\n", b":1\r\n").await;
+
+        let mut export = Vec::new();
+        src.lock().unwrap().export(&mut export).unwrap();
+        let mut reply = format!("${}\r\n", export.len()).into_bytes();
+        reply.extend_from_slice(&export);
+        reply.extend_from_slice(b"\r\n");
+        exchange(&mut client, b"*1\r\n$6\r\nEXPORT\r\n", &reply).await;
+
+        let stores = dst_files.iter().map(|file| Arc::new(Mutex::new(libactionkv::open(&file.0).unwrap()))).collect();
+        let databases: Databases = Arc::new(Keyspace { stores, shards: 2 });
+        let (mut client, socket) = tokio::io::duplex(1 << 16);
+        tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+        let mut request = format!("*2\r\n$6\r\nimport\r\n${}\r\n", export.len()).into_bytes();
+        request.extend_from_slice(&export);
+        request.extend_from_slice(b"\r\n");
+        exchange(&mut client, &request, b":2\r\n").await;
+        exchange(&mut client, b"*2\r\n$3\r\nget\r\n$1\r\na\r\n", b"$1\r\n1\r\n").await;
+        exchange(&mut client, b"*4\r\n$6\r\nlrange\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n", b"*1\r\n$1\r\nx\r\n").await;
+        exchange(&mut client, b"*2\r\n$6\r\nimport\r\n$4\r\nnope\r\n", b"-ERR failed to fill whole buffer\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn each_connection_keeps_its_own_name() {
+        let file = TempFile::new("clientname");
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(libactionkv::open(&file.0).unwrap()))], shards: 1 });
+        let (mut named, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+        let (mut other, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::new(PubSub::new(false)), None, ServerSettings::default()));
+
+        exchange(&mut named, b"*3\r\n$6\r\nclient\r\n$7\r\nsetname\r\n$6\r\nworker\r\n", b"+OK\r\n").await;
+        exchange(&mut named, b"*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n", b"$6\r\nworker\r\n").await;
+        exchange(&mut other, b"*2\r\n$6\r\nclient\r\n$7\r\ngetname\r\n", b"$0\r\n\r\n").await;
+        exchange(
+            &mut other,
+            b"*3\r\n$6\r\nclient\r\n$7\r\nsetname\r\n$3\r\na b\r\n",
+            b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n",
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn connections_past_maxclients_are_refused_and_idle_ones_closed() {
+        let file = TempFile::new("limits");
+        let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::new(Mutex::new(libactionkv::open(&file.0).unwrap()))], shards: 1 });
+        let stats = Arc::new(Stats::new());
+        let pubsub = Arc::new(PubSub::new(false));
+        let limits = ClientLimits { maxclients: Some(1), timeout: Some(Duration::from_millis(100)) };
+        let settings = ServerSettings { limits, ..ServerSettings::default() };
+
+        let (mut first, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::clone(&stats), Arc::clone(&pubsub), None, settings.clone()));
+        exchange(&mut first, b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n", b"+OK\r\n").await;
+        let (mut second, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, Arc::clone(&databases), Arc::clone(&stats), Arc::clone(&pubsub), None, settings.clone()));
+        let mut refusal = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), second.read_to_end(&mut refusal)).await.unwrap().unwrap();
+        assert_eq!(String::from_utf8_lossy(&refusal), "-ERR max number of clients reached\r\n");
+
+        // Left alone, the first connection is closed, which frees its slot.
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), first.read_to_end(&mut rest)).await.unwrap().unwrap();
+        assert!(rest.is_empty());
+        let (mut third, socket) = tokio::io::duplex(4096);
+        tokio::spawn(process(socket, databases, stats, pubsub, None, settings));
+        exchange(&mut third, b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n", b"$5\r\nvalue\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn group_commit_acknowledges_writes_after_the_sync() {
+        let file = TempFile::new("group-commit");
+        let mut store = libactionkv::open(&file.0).unwrap();
+        store.load().unwrap();
+        let db = Arc::new(Mutex::new(store));
+        let commit = Arc::new(GroupCommit::new(2));
+        // A window far longer than the test, so only a full batch syncs.
+        tokio::spawn(group_commit(Arc::clone(&db), Arc::clone(&commit), Duration::from_secs(3600)));
+        let commits: GroupCommits = Arc::new(vec![commit]);
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (client, socket) = tokio::io::duplex(4096);
+            let databases: Databases = Arc::new(Keyspace { stores: vec![Arc::clone(&db)], shards: 1 });
+            tokio::spawn(process(socket, databases, Arc::new(Stats::new()), Arc::new(PubSub::new(false)), Some(Arc::clone(&commits)), ServerSettings::default()));
+            clients.push(client);
+        }
+        let set = b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        clients[0].write_all(set).await.unwrap();
+        let mut reply = [0; 5];
+        // Alone, the write waits for a batch that never fills.
+        assert!(tokio::time::timeout(Duration::from_millis(100), clients[0].read_exact(&mut reply)).await.is_err());
+        exchange(&mut clients[1], set, b"+OK\r\n").await;
+        exchange(&mut clients[0], b"", b"+OK\r\n").await;
+    }
+}
@@ -0,0 +1,141 @@
+//! An async front to a store, for embedding it in a tokio application.
+//!
+//! `AKVMEM` does plain blocking file I/O, so calling it straight from an
+//! async task stalls every other task on that runtime thread until the disk
+//! answers. `AsyncStore` keeps the store behind a mutex and runs each call
+//! on tokio's blocking thread pool with `spawn_blocking`; the calling task
+//! only awaits the result. Calls run one at a time, in the order they take
+//! the lock, exactly as they would on a `Mutex<AKVMEM>` shared between
+//! threads. Clones share the store, so a handle can be given to every task.
+//! A call that panics fails with an error; the store stays usable, as the
+//! server's stores do after a panicking command, so a closure passed to
+//! `run` shouldn't panic part way through a change it needs to finish.
+
+use std::fs::File;
+use std::io::{Error, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::{open_with_options, ByteString, OpenConfig, Storage, AKVMEM};
+
+/// A store whose operations run on the blocking thread pool. Needs a tokio
+/// runtime.
+pub struct AsyncStore<S: Storage = File> {
+    store: Arc<Mutex<AKVMEM<S>>>,
+}
+
+impl<S: Storage> Clone for AsyncStore<S> {
+    fn clone(&self) -> Self {
+        AsyncStore { store: Arc::clone(&self.store) }
+    }
+}
+
+impl AsyncStore {
+    /// Opens the data file at `path` and loads its index, both off the
+    /// runtime thread.
+    pub async fn open(path: PathBuf, config: OpenConfig) -> Result<AsyncStore> {
+        let store = spawn(move || {
+            let mut store = open_with_options(&path, config)?;
+            store.load()?;
+            Ok(store)
+        }).await?;
+        Ok(AsyncStore::new(store))
+    }
+}
+
+impl<S: Storage + Send + 'static> AsyncStore<S> {
+    /// Wraps a store that is already open and loaded.
+    pub fn new(store: AKVMEM<S>) -> Self {
+        AsyncStore { store: Arc::new(Mutex::new(store)) }
+    }
+
+    /// Runs `f` on the store from the blocking thread pool, for anything
+    /// the methods below don't cover.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut AKVMEM<S>) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        spawn(move || f(&mut store.lock().unwrap_or_else(PoisonError::into_inner))).await
+    }
+
+    pub async fn get(&self, key: ByteString) -> Result<Option<ByteString>> {
+        self.run(move |store| store.get(&key)).await
+    }
+
+    pub async fn exists(&self, key: ByteString) -> Result<bool> {
+        self.run(move |store| store.exists(&key)).await
+    }
+
+    pub async fn insert(&self, key: ByteString, value: ByteString) -> Result<u64> {
+        self.run(move |store| store.insert(&key, &value)).await
+    }
+
+    /// `expires_at` is a Unix time in milliseconds.
+    pub async fn insert_with_expiry(&self, key: ByteString, value: ByteString, expires_at: Option<u64>) -> Result<u64> {
+        self.run(move |store| store.insert_with_expiry(&key, &value, expires_at)).await
+    }
+
+    pub async fn delete(&self, key: ByteString) -> Result<()> {
+        self.run(move |store| store.delete(&key)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|store| store.flush()).await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.run(|store| store.sync()).await
+    }
+
+    /// Compacts the data file. Other calls wait until it is done.
+    pub async fn compact(&self) -> Result<()> {
+        self.run(|store| store.compact()).await
+    }
+}
+
+/// Runs `f` with `spawn_blocking`, turning a panic in it into an error.
+async fn spawn<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncStore;
+    use crate::open_in_memory;
+
+    #[tokio::test]
+    async fn calls_from_many_tasks_reach_the_same_store() {
+        let store = AsyncStore::new(open_in_memory().unwrap());
+        let writers: Vec<_> = (0..8u8)
+            .map(|n| {
+                let store = store.clone();
+                tokio::spawn(async move { store.insert(vec![b'k', n], vec![n]).await })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap().unwrap();
+        }
+
+        assert_eq!(store.get(b"k\x03".to_vec()).await.unwrap(), Some(vec![3]));
+        store.delete(b"k\x03".to_vec()).await.unwrap();
+        assert!(!store.exists(b"k\x03".to_vec()).await.unwrap());
+        assert_eq!(store.run(|store| Ok(store.index.len())).await.unwrap(), 7);
+        assert!(store.insert(Vec::new(), b"v".to_vec()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_call_leaves_the_store_usable() {
+        let store = AsyncStore::new(open_in_memory().unwrap());
+        store.insert(b"k".to_vec(), b"v".to_vec()).await.unwrap();
+        assert!(store.run(|_| -> std::io::Result<()> { panic!("in a call") }).await.is_err());
+        assert_eq!(store.get(b"k".to_vec()).await.unwrap(), Some(b"v".to_vec()));
+    }
+}
@@ -1,66 +1,98 @@
+//! A typed client for the server, so Rust code can use the store without
+//! building RESP frames by hand. It speaks the same `Frame`s over the same
+//! `Connection` the server uses.
+
+use std::io::{Error, ErrorKind, Result};
+
 use bytes::Bytes;
-use mini_redis::client;
-use tokio::{self, sync};
-
-#[derive(Debug)]
-enum Command<'a> {
-    Get {
-        key: &'a str,
-        resp: Responder<Option<Bytes>>
-    },
-    Set{
-        key: &'a str, 
-        value: String,
-        resp: Responder<()>
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::server_helpers::{Connection, Frame};
+
+pub struct Client<S> {
+    connection: Connection<S>,
+}
+
+impl Client<TcpStream> {
+    /// Connects to a server listening on `addr`, e.g. `127.0.0.1:6379`.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Client::new(TcpStream::connect(addr).await?))
     }
 }
 
-type Responder<T> = sync::oneshot::Sender<mini_redis::Result<T>>;
-
-#[tokio::main]
-async fn main() {
-    use Command::*;
-    let mut client = client::connect("127.0.0.1:6379").await.unwrap();
-    let (tx, mut rx): (sync::mpsc::Sender<Command>, sync::mpsc::Receiver<Command>) = sync::mpsc::channel(32);
-    let tx1 = tx.clone();
-    let tx2 = tx.clone();
-    let manager = tokio::spawn(async move {
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                Get{key, resp} => {
-                    let res = client.get(key).await;
-                    resp.send(res);
-                }
-                Set{key, value, resp} => {
-                    let res = client.set(key, value.into()).await;
-
-                    resp.send(res);
-                }
-            }
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
+    /// Talks to a server over an already open stream.
+    pub fn new(stream: S) -> Self {
+        Client { connection: Connection::new(stream) }
+    }
+
+    /// The value under `key`, or None if it is missing.
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(&[b"get", key]).await? {
+            Frame::Bulk(value) => Ok(Some(value.to_vec())),
+            Frame::Null => Ok(None),
+            frame => Err(unexpected(&frame)),
         }
-    });
+    }
+
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.request_ok(&[b"set", key, value]).await
+    }
 
-    let t1 = tokio::spawn(async move {
-        let (tx, mut rx) = sync::oneshot::channel();
-        tx1.send(Command::Get{key: "hello", resp: tx}).await;
-        let resp = rx.await;
+    pub async fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.request_ok(&[b"delete", key]).await
+    }
 
-        println!("Get return value: {:?}", resp);
-        
-    });
+    pub async fn update(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.request_ok(&[b"update", key, value]).await
+    }
 
-    let t2 = tokio::spawn(async move {
-        let (tx, mut rx) = sync::oneshot::channel();
-        tx2.send(Command::Set{key: "foo", value: "bar".to_string(), resp: tx}).await;
+    /// Sends a command whose reply is a plain `OK`.
+    async fn request_ok(&mut self, args: &[&[u8]]) -> Result<()> {
+        match self.request(args).await? {
+            Frame::Simple(_) => Ok(()),
+            frame => Err(unexpected(&frame)),
+        }
+    }
 
-        let resp = rx.await;
+    /// Sends `args` as one command and waits for the reply. An error reply
+    /// comes back as an `Err` carrying the server's message.
+    async fn request(&mut self, args: &[&[u8]]) -> Result<Frame> {
+        let command = Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg))).collect());
+        self.connection.write_frame(command).await?;
+        match self.connection.read_frame().await? {
+            Some(Frame::Error(msg)) => Err(Error::other(msg)),
+            Some(frame) => Ok(frame),
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "Server closed the connection")),
+        }
+    }
+}
+
+fn unexpected(frame: &Frame) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Unexpected reply {:?}", frame))
+}
 
-        println!("Set return value: {:?}", resp);
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+    #[tokio::test]
+    async fn replies_are_turned_into_values() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = Client::new(stream);
+        // The client waits for each reply, so they can all be queued up front.
+        server.write_all(b"+OK\r\n$5\r\nvalue\r\n$-1\r\n-ERR boom\r\n").await.unwrap();
 
+        client.set(b"key", b"value").await.unwrap();
+        assert_eq!(client.get(b"key").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(client.get(b"missing").await.unwrap(), None);
+        assert_eq!(client.delete(b"key").await.unwrap_err().to_string(), "ERR boom");
 
-    t1.await.unwrap();
-    t2.await.unwrap();
-    manager.await.unwrap();
-}
\ No newline at end of file
+        drop(client);
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.starts_with(b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Hash and list values layered on top of the byte store. Each is kept as a
+//! single record whose value is the bincode encoded map or list, so every
+//! change rewrites the whole value. That is fine for the small structured
+//! values this is meant for.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::{ByteString, Storage, ValueType, AKVMEM};
+
+type Hash = BTreeMap<ByteString, ByteString>;
+type List = VecDeque<ByteString>;
+
+/// Reply for a command run against a key holding another type of value.
+pub const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+pub(crate) fn wrong_type() -> Error {
+    Error::new(ErrorKind::InvalidInput, WRONG_TYPE)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(value: &[u8]) -> Result<T> {
+    bincode::deserialize(value).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> Result<ByteString> {
+    bincode::serialize(value).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))
+}
+
+impl<S: Storage> AKVMEM<S> {
+    /// The hash under `key` and its expiry, or None if the key is missing.
+    fn load_hash(&self, key: &ByteString) -> Result<Option<(Hash, Option<u64>)>> {
+        match self.get_record(key)? {
+            Some(kv) if kv.value_type == ValueType::Hash => Ok(Some((decode(&kv.value)?, kv.expires_at))),
+            Some(_) => Err(wrong_type()),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets `field` in the hash under `key`, creating the hash if needed.
+    /// Returns true if the field is new.
+    pub fn hset(&mut self, key: &ByteString, field: &ByteString, value: &ByteString) -> Result<bool> {
+        let (mut hash, expires_at) = self.load_hash(key)?.unwrap_or_default();
+        let added = hash.insert(field.clone(), value.clone()).is_none();
+        self.put(key, &encode(&hash)?, expires_at, ValueType::Hash)?;
+        Ok(added)
+    }
+
+    pub fn hget(&self, key: &ByteString, field: &ByteString) -> Result<Option<ByteString>> {
+        Ok(self.load_hash(key)?.and_then(|(mut hash, _)| hash.remove(field)))
+    }
+
+    /// Every field and value of the hash under `key`, ordered by field.
+    pub fn hgetall(&self, key: &ByteString) -> Result<Vec<(ByteString, ByteString)>> {
+        Ok(self.load_hash(key)?.map(|(hash, _)| hash.into_iter().collect()).unwrap_or_default())
+    }
+
+    /// Up to `count` fields of the hash under `key` with their values, from
+    /// position `cursor` in field order, and the cursor to carry on from,
+    /// which is 0 once the last field has been returned. The cursor is an
+    /// index, so fields added or removed between calls can shift what the
+    /// next call starts at.
+    pub fn hscan(&self, key: &ByteString, cursor: u64, count: usize) -> Result<(u64, Vec<(ByteString, ByteString)>)> {
+        let hash = match self.load_hash(key)? {
+            Some((hash, _)) => hash,
+            None => return Ok((0, Vec::new())),
+        };
+        let start = usize::try_from(cursor).unwrap_or(usize::MAX);
+        // One past `count` tells whether anything is left after this page.
+        let mut fields: Vec<_> = hash.into_iter().skip(start).take(count.saturating_add(1)).collect();
+        if fields.len() <= count {
+            return Ok((0, fields))
+        }
+        fields.truncate(count);
+        Ok((cursor + count as u64, fields))
+    }
+
+    /// Removes `field` from the hash under `key`, deleting the key once the
+    /// hash is empty. Returns true if the field existed.
+    pub fn hdel(&mut self, key: &ByteString, field: &ByteString) -> Result<bool> {
+        let (mut hash, expires_at) = match self.load_hash(key)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        if hash.remove(field).is_none() {
+            return Ok(false)
+        }
+        if hash.is_empty() {
+            self.delete(key)?;
+        } else {
+            self.put(key, &encode(&hash)?, expires_at, ValueType::Hash)?;
+        }
+        Ok(true)
+    }
+
+    /// The list under `key` and its expiry, or None if the key is missing.
+    fn load_list(&self, key: &ByteString) -> Result<Option<(List, Option<u64>)>> {
+        match self.get_record(key)? {
+            Some(kv) if kv.value_type == ValueType::List => Ok(Some((decode(&kv.value)?, kv.expires_at))),
+            Some(_) => Err(wrong_type()),
+            None => Ok(None),
+        }
+    }
+
+    /// Pushes `values` onto the head of the list under `key` one after the
+    /// other, so the last value ends up first. Returns the new length.
+    pub fn lpush(&mut self, key: &ByteString, values: &[ByteString]) -> Result<usize> {
+        let (mut list, expires_at) = self.load_list(key)?.unwrap_or_default();
+        for value in values {
+            list.push_front(value.clone());
+        }
+        self.put(key, &encode(&list)?, expires_at, ValueType::List)?;
+        Ok(list.len())
+    }
+
+    /// Appends `values` to the tail of the list under `key`. Returns the new
+    /// length.
+    pub fn rpush(&mut self, key: &ByteString, values: &[ByteString]) -> Result<usize> {
+        let (mut list, expires_at) = self.load_list(key)?.unwrap_or_default();
+        list.extend(values.iter().cloned());
+        self.put(key, &encode(&list)?, expires_at, ValueType::List)?;
+        Ok(list.len())
+    }
+
+    /// Elements `start` through `stop` inclusive of the list under `key`.
+    /// Negative indexes count back from the end, as in redis.
+    pub fn lrange(&self, key: &ByteString, start: i64, stop: i64) -> Result<Vec<ByteString>> {
+        let list = match self.load_list(key)? {
+            Some((list, _)) => list,
+            None => return Ok(vec![]),
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop {
+            return Ok(vec![])
+        }
+        Ok(list.into_iter().skip(start as usize).take((stop - start + 1) as usize).collect())
+    }
+
+    /// Removes and returns the head of the list under `key`, deleting the
+    /// key once the list is empty. None if the key is missing.
+    pub fn lpop(&mut self, key: &ByteString) -> Result<Option<ByteString>> {
+        let (mut list, expires_at) = match self.load_list(key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let head = list.pop_front();
+        if list.is_empty() {
+            self.delete(key)?;
+        } else {
+            self.put(key, &encode(&list)?, expires_at, ValueType::List)?;
+        }
+        Ok(head)
+    }
+
+    pub fn llen(&self, key: &ByteString) -> Result<usize> {
+        Ok(self.load_list(key)?.map(|(list, _)| list.len()).unwrap_or(0))
+    }
+}
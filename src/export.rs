@@ -0,0 +1,172 @@
+//! Moving a whole keyspace between stores, e.g. on different servers. An
+//! export is a stream of length-prefixed frames, each a live pair encoded
+//! the way DUMP encodes one, between a short header and an empty frame that
+//! marks the end. The end marker is what tells a complete export from one
+//! cut short.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{unix_millis, KeyValuePair, Storage, AKVMEM};
+
+/// Identifies an export stream.
+const EXPORT_MAGIC: &[u8; 4] = b"AKVX";
+/// Version of the export layout.
+const EXPORT_VERSION: u8 = 1;
+
+/// Writes an export of one or more stores to `W`.
+pub struct Exporter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Exporter<W> {
+    /// Starts an export by writing its header.
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(EXPORT_MAGIC)?;
+        out.write_u8(EXPORT_VERSION)?;
+        Ok(Exporter { out })
+    }
+
+    /// Adds every live pair of `store` and returns how many that was.
+    /// Each is read with `get_at` from where the index points, so records
+    /// that were superseded or deleted never make it in.
+    pub fn add<S: Storage>(&mut self, store: &AKVMEM<S>) -> Result<u64> {
+        let now = unix_millis();
+        let mut exported = 0;
+        for entry in store.index.values() {
+            let kv = store.get_at(entry.position)?;
+            if kv.tombstone || kv.is_expired(now) {
+                continue;
+            }
+            let frame = bincode::serialize(&kv).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+            self.out.write_u64::<LittleEndian>(frame.len() as u64)?;
+            self.out.write_all(&frame)?;
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    /// Writes the end marker and hands back the writer, flushed.
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_u64::<LittleEndian>(0)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Reads the pairs of an export back, one per `next`. Fails with
+/// UnexpectedEof if the stream ends before the end marker.
+pub struct ExportReader<R: Read> {
+    src: R,
+    done: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+    /// Checks the header of the export `src` is at.
+    pub fn new(mut src: R) -> Result<Self> {
+        let mut header = [0; 5];
+        src.read_exact(&mut header)?;
+        if &header[..4] != EXPORT_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not an export"))
+        }
+        if header[4] != EXPORT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported export version {}", header[4])))
+        }
+        Ok(ExportReader { src, done: false })
+    }
+
+    fn read_pair(&mut self) -> Result<Option<KeyValuePair>> {
+        let len = self.src.read_u64::<LittleEndian>()?;
+        if len == 0 {
+            return Ok(None)
+        }
+        let mut frame = Vec::new();
+        (&mut self.src).take(len).read_to_end(&mut frame)?;
+        if frame.len() as u64 != len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Export is truncated"))
+        }
+        bincode::deserialize(&frame).map(Some).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))
+    }
+}
+
+impl<R: Read> Iterator for ExportReader<R> {
+    type Item = Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        let pair = self.read_pair();
+        // Nothing after an error or the end marker can be trusted.
+        self.done = !matches!(pair, Ok(Some(_)));
+        pair.transpose()
+    }
+}
+
+impl<S: Storage> AKVMEM<S> {
+    /// Writes every live pair to `out` as an export. Returns how many were
+    /// written.
+    pub fn export<W: Write>(&self, out: W) -> Result<u64> {
+        let mut exporter = Exporter::new(out)?;
+        let exported = exporter.add(self)?;
+        exporter.finish()?;
+        Ok(exported)
+    }
+
+    /// Stores every pair of the export `src` is at, replacing keys that
+    /// already exist. Returns how many were stored. Pairs that have expired
+    /// since the export are skipped. A bad or truncated export fails part
+    /// way, with the pairs before the damage already stored.
+    pub fn import<R: Read>(&mut self, src: R) -> Result<u64> {
+        let mut imported = 0;
+        for kv in ExportReader::new(src)? {
+            if self.import_pair(&kv?)? {
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Stores one pair read by an `ExportReader` under its key, with its
+    /// type and expiry. Returns false without writing if it has expired.
+    pub fn import_pair(&mut self, kv: &KeyValuePair) -> Result<bool> {
+        if kv.is_expired(unix_millis()) {
+            return Ok(false)
+        }
+        self.put(&kv.key, &kv.value, kv.expires_at, kv.value_type)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{open_in_memory, unix_millis};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn an_export_imports_into_another_store() {
+        let mut src = open_in_memory().unwrap();
+        src.insert(&b"a".to_vec(), &b"1".to_vec()).unwrap();
+        src.insert(&b"a".to_vec(), &b"2".to_vec()).unwrap();
+        src.insert(&b"gone".to_vec(), &b"x".to_vec()).unwrap();
+        src.delete(&b"gone".to_vec()).unwrap();
+        src.insert_with_expiry(&b"ttl".to_vec(), &b"t".to_vec(), Some(unix_millis() + 60_000)).unwrap();
+        src.hset(&b"h".to_vec(), &b"f".to_vec(), &b"v".to_vec()).unwrap();
+
+        let mut export = Vec::new();
+        assert_eq!(src.export(&mut export).unwrap(), 3);
+
+        let mut dst = open_in_memory().unwrap();
+        dst.insert(&b"a".to_vec(), &b"old".to_vec()).unwrap();
+        assert_eq!(dst.import(&export[..]).unwrap(), 3);
+        assert_eq!(dst.get(&b"a".to_vec()).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(dst.get(&b"gone".to_vec()).unwrap(), None);
+        assert_eq!(dst.get_record(&b"ttl".to_vec()).unwrap().unwrap().expires_at, src.get_record(&b"ttl".to_vec()).unwrap().unwrap().expires_at);
+        assert_eq!(dst.hget(&b"h".to_vec(), &b"f".to_vec()).unwrap(), Some(b"v".to_vec()));
+
+        let mut dst = open_in_memory().unwrap();
+        assert_eq!(dst.import(&export[..export.len() - 1]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(dst.import(&b"DUMP\x01"[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,161 @@
+//! The in-memory map from each live key to where its record starts. A hash
+//! map is the quickest for point lookups, which is all most workloads do;
+//! a BTreeMap keeps the keys sorted so that prefix scans and ordered
+//! iteration don't have to look at every key.
+
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::ops;
+
+use crate::{ByteString, IndexEntry};
+
+/// Which map backs a store's index, picked at open time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A HashMap. Keys come back in no particular order.
+    Hashed,
+    /// A BTreeMap. Keys come back in byte order.
+    Ordered,
+}
+
+#[derive(Debug)]
+pub enum Index {
+    Hashed(HashMap<ByteString, IndexEntry>),
+    Ordered(BTreeMap<ByteString, IndexEntry>),
+}
+
+impl Index {
+    pub fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hashed => Index::Hashed(HashMap::new()),
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            Index::Hashed(_) => IndexKind::Hashed,
+            Index::Ordered(_) => IndexKind::Ordered,
+        }
+    }
+
+    /// An empty index of the same kind, with room for `capacity` keys where
+    /// the map supports it.
+    pub fn empty_like(&self, capacity: usize) -> Self {
+        match self {
+            Index::Hashed(_) => Index::Hashed(HashMap::with_capacity(capacity)),
+            Index::Ordered(_) => Index::Ordered(BTreeMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
+        match self {
+            Index::Hashed(map) => map.get(key),
+            Index::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: ByteString, entry: IndexEntry) -> Option<IndexEntry> {
+        match self {
+            Index::Hashed(map) => map.insert(key, entry),
+            Index::Ordered(map) => map.insert(key, entry),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        match self {
+            Index::Hashed(map) => map.remove(key),
+            Index::Ordered(map) => map.remove(key),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            Index::Hashed(map) => map.clear(),
+            Index::Ordered(map) => map.clear(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Hashed(map) => map.len(),
+            Index::Ordered(map) => map.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every key and its entry, in byte order for an ordered index.
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Index::Hashed(map) => Iter::Hashed(map.iter()),
+            Index::Ordered(map) => Iter::Ordered(map.iter()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &ByteString> + Clone {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &IndexEntry> + Clone {
+        self.iter().map(|(_, entry)| entry)
+    }
+
+    /// The keys that start with `prefix`, with their entries. An ordered
+    /// index seeks straight to them; a hashed one has to check every key.
+    pub fn prefix<'a>(&'a self, prefix: &'a [u8]) -> Box<dyn Iterator<Item = (&'a ByteString, &'a IndexEntry)> + 'a> {
+        match self {
+            Index::Hashed(map) => Box::new(map.iter().filter(move |(key, _)| key.starts_with(prefix))),
+            Index::Ordered(map) => Box::new(
+                map.range::<[u8], _>((ops::Bound::Included(prefix), ops::Bound::Unbounded))
+                    .take_while(move |(key, _)| key.starts_with(prefix)),
+            ),
+        }
+    }
+}
+
+impl ops::Index<&[u8]> for Index {
+    type Output = IndexEntry;
+
+    fn index(&self, key: &[u8]) -> &IndexEntry {
+        self.get(key).expect("key not in index")
+    }
+}
+
+impl<'a> IntoIterator for &'a Index {
+    type Item = (&'a ByteString, &'a IndexEntry);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[derive(Clone)]
+pub enum Iter<'a> {
+    Hashed(hash_map::Iter<'a, ByteString, IndexEntry>),
+    Ordered(btree_map::Iter<'a, ByteString, IndexEntry>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a ByteString, &'a IndexEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Hashed(iter) => iter.next(),
+            Iter::Ordered(iter) => iter.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Iter::Hashed(iter) => iter.size_hint(),
+            Iter::Ordered(iter) => iter.size_hint(),
+        }
+    }
+}
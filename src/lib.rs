@@ -1,90 +1,967 @@
+pub mod client;
 pub mod server_helpers;
+mod async_store;
+mod collections;
+mod export;
+mod index;
+mod storage;
+
+pub use async_store::AsyncStore;
+pub use collections::WRONG_TYPE;
+pub use export::{ExportReader, Exporter};
+pub use index::{Index, IndexKind};
+pub use storage::{Memory, Storage};
 
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
 use std::io::Write;
 use std::io::{BufReader, BufWriter};
 use std::io::{ErrorKind, Error};
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cell::Cell;
 use std::io::Result;
 use std::collections::HashMap;
-use std::usize;
-use byteorder::LittleEndian;
+use std::convert::TryFrom;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use byteorder::{ReadBytesExt, WriteBytesExt};
-use crc::crc32;
+use bytes::Bytes;
+use crc::{crc32, Hasher32};
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+use rand::Rng;
+use rand::seq::IteratorRandom;
 use serde_derive::{Deserialize, Serialize};
+use storage::lock_exclusive;
+use std::marker::PhantomData;
+use std::mem;
 
 
 
 type ByteString = Vec<u8>;
 
+/// What a record's value holds. Hashes and lists are stored as one
+/// serialized value; the tag lets commands for one type refuse keys of
+/// another.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    Hash,
+    List,
+}
+
+impl ValueType {
+    fn to_flags(self) -> u8 {
+        let tag = match self {
+            ValueType::String => 0,
+            ValueType::Hash => 1,
+            ValueType::List => 2,
+        };
+        tag << TYPE_SHIFT
+    }
+
+    fn from_flags(flags: u8) -> Result<ValueType> {
+        match (flags & TYPE_MASK) >> TYPE_SHIFT {
+            0 => Ok(ValueType::String),
+            1 => Ok(ValueType::Hash),
+            2 => Ok(ValueType::List),
+            tag => Err(Error::new(ErrorKind::InvalidData, format!("Unknown value type {} in record", tag))),
+        }
+    }
+}
+
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyValuePair {
     key: ByteString,
-    value: ByteString
+    value: ByteString,
+    /// Unix time in milliseconds after which the record is ignored.
+    expires_at: Option<u64>,
+    value_type: ValueType,
+    /// Marks a deletion. Never part of a DUMP payload.
+    #[serde(skip)]
+    tombstone: bool,
+    /// Unix time in milliseconds the record was written at. A restored
+    /// value is stamped when it is restored, so this isn't dumped either.
+    #[serde(skip)]
+    written_at: u64,
+}
+
+impl KeyValuePair {
+    pub fn key(&self) -> &ByteString {
+        &self.key
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The fixed-size part of a record that precedes its key and value.
+struct RecordHeader {
+    checksum: u32,
+    key_len: u64,
+    val_len: u64,
+    /// Unix time in milliseconds, or 0 for a record that never expires.
+    expires_at: u64,
+    /// Unix time in milliseconds the record was written at.
+    written_at: u64,
+    flags: u8,
+    /// The byte order the header was read in, which the checksum covers.
+    endianness: Endianness,
+}
+
+/// Record flag set on the record `delete` writes.
+const FLAG_TOMBSTONE: u8 = 1;
+/// Record flag bits holding the `ValueType`.
+const TYPE_MASK: u8 = 0b110;
+const TYPE_SHIFT: u8 = 1;
+
+impl RecordHeader {
+    fn read_from<R: Read>(f: &mut R, endianness: Endianness) -> Result<RecordHeader> {
+        Ok(RecordHeader {
+            checksum: endianness.read_u32(f)?,
+            key_len: endianness.read_u64(f)?,
+            val_len: endianness.read_u64(f)?,
+            expires_at: endianness.read_u64(f)?,
+            written_at: endianness.read_u64(f)?,
+            flags: f.read_u8()?,
+            endianness,
+        })
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        if self.expires_at == 0 { None } else { Some(self.expires_at) }
+    }
+
+    fn record_len(&self) -> u64 {
+        RECORD_HEADER_LEN + self.key_len + self.val_len
+    }
+
+    /// Checksum of everything in the record after the checksum itself: the
+    /// rest of the header and then `data`, the key and value. Covering the
+    /// header means a damaged length or expiry is caught too.
+    fn checksum_of(&self, data: &[u8], checksum: Checksum) -> u32 {
+        let mut fields = [0u8; RECORD_HEADER_LEN as usize - 4];
+        self.endianness.write_u64(&mut fields[..8], self.key_len);
+        self.endianness.write_u64(&mut fields[8..16], self.val_len);
+        self.endianness.write_u64(&mut fields[16..24], self.expires_at);
+        self.endianness.write_u64(&mut fields[24..32], self.written_at);
+        fields[32] = self.flags;
+        checksum.of(&[&fields, data])
+    }
+}
+
+/// The byte order of the numbers in record headers, recorded in the data
+/// file header. New files are little-endian on every platform; a file that
+/// says otherwise is read, and appended to, in its own order rather than
+/// being misread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    fn to_byte(self) -> u8 {
+        match self {
+            Endianness::Little => 0,
+            Endianness::Big => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Endianness> {
+        match byte {
+            0 => Ok(Endianness::Little),
+            1 => Ok(Endianness::Big),
+            other => Err(Error::new(ErrorKind::InvalidData, format!("Unknown byte order {} in data file header", other))),
+        }
+    }
+
+    fn read_u32<R: Read>(self, f: &mut R) -> Result<u32> {
+        match self {
+            Endianness::Little => f.read_u32::<LittleEndian>(),
+            Endianness::Big => f.read_u32::<BigEndian>(),
+        }
+    }
+
+    fn read_u64<R: Read>(self, f: &mut R) -> Result<u64> {
+        match self {
+            Endianness::Little => f.read_u64::<LittleEndian>(),
+            Endianness::Big => f.read_u64::<BigEndian>(),
+        }
+    }
+
+    fn write_u32(self, buf: &mut [u8], n: u32) {
+        match self {
+            Endianness::Little => LittleEndian::write_u32(buf, n),
+            Endianness::Big => BigEndian::write_u32(buf, n),
+        }
+    }
+
+    fn write_u64(self, buf: &mut [u8], n: u64) {
+        match self {
+            Endianness::Little => LittleEndian::write_u64(buf, n),
+            Endianness::Big => BigEndian::write_u64(buf, n),
+        }
+    }
+
+    fn decode_u64(self, buf: &[u8]) -> u64 {
+        match self {
+            Endianness::Little => LittleEndian::read_u64(buf),
+            Endianness::Big => BigEndian::read_u64(buf),
+        }
+    }
+}
+
+/// How records are checksummed. Chosen when a data file is created and
+/// recorded in its header, so the file is always read back with the
+/// algorithm it was written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC32 (IEEE). The default.
+    Crc32,
+    /// The low 32 bits of XXH64, which is several times faster than CRC32
+    /// on large values.
+    XxHash,
+}
+
+impl Checksum {
+    fn to_byte(self) -> u8 {
+        match self {
+            Checksum::Crc32 => 0,
+            Checksum::XxHash => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Checksum> {
+        match byte {
+            0 => Ok(Checksum::Crc32),
+            1 => Ok(Checksum::XxHash),
+            other => Err(Error::new(ErrorKind::InvalidData, format!("Unknown checksum algorithm {} in data file header", other))),
+        }
+    }
+
+    /// Checksum of `parts` laid end to end.
+    fn of(self, parts: &[&[u8]]) -> u32 {
+        match self {
+            Checksum::Crc32 => {
+                let mut digest = crc32::Digest::new(crc32::IEEE);
+                for part in parts {
+                    Hasher32::write(&mut digest, part);
+                }
+                digest.sum32()
+            }
+            Checksum::XxHash => {
+                let mut hasher = XxHash64::with_seed(0);
+                for part in parts {
+                    hasher.write(part);
+                }
+                hasher.finish() as u32
+            }
+        }
+    }
+}
+
+/// Current Unix time in milliseconds, the unit record expiry is stored in.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Where the live record for a key starts and how long it is, and when the
+/// key was last written and used.
+#[derive(Debug)]
+pub struct IndexEntry {
+    pub position: u64,
+    /// Length of the record, header included, so a read can fetch all of it
+    /// at once.
+    pub len: u64,
+    /// Unix time in milliseconds the live record was written at.
+    pub written_at: u64,
+    /// Unix time in milliseconds of the last read or write, for LRU
+    /// eviction. A Cell so that reads through `&self` can bump it.
+    last_access: Cell<u64>,
+}
+
+impl IndexEntry {
+    fn new(position: u64, len: u64, written_at: u64, last_access: u64) -> Self {
+        IndexEntry { position, len, written_at, last_access: Cell::new(last_access) }
+    }
+
+    pub fn last_access(&self) -> u64 {
+        self.last_access.get()
+    }
+}
+
+/// A store whose log is kept in `S`, the data file unless opened otherwise.
+pub struct AKVMEM<S: Storage = File> {
+    f: S,
+    path: PathBuf,
+    read_only: bool,
+    dead_bytes: u64,
+    checksum: Checksum,
+    endianness: Endianness,
+    maxmemory: Option<u64>,
+    eviction_policy: EvictionPolicy,
+    /// Keys `unlink` has taken out of the index whose tombstones are still
+    /// to be written, oldest first.
+    unlinked: Vec<ByteString>,
+    /// Appends not yet handed to `f`, which start at `pending_from` in the
+    /// log. Only used when `write_buffer` is non-zero.
+    pending: Vec<u8>,
+    pending_from: u64,
+    write_buffer: usize,
+    verify_on_read: bool,
+    read_buffer: usize,
+    /// Set by `check_file` once the data file has gone from `path`.
+    detached: bool,
+    pub index: Index
+}
+
+/// What a write does when the live records would grow past `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse the write with an OOM error.
+    NoEviction,
+    /// Delete keys picked at random until the write fits.
+    AllKeysRandom,
+    /// Delete the least recently read or written keys until the write fits.
+    AllKeysLru,
 }
-pub struct AKVMEM {
-    f: File,
-    pub index: HashMap<ByteString, u64>
+
+/// Controls how `open_with_options` opens the data file.
+#[derive(Debug, Clone, Copy)]
+pub struct OpenConfig {
+    /// Create the file (with a fresh header) if it doesn't exist.
+    /// Ignored when `read_only` is set.
+    pub create: bool,
+    /// Open without write access. Every write returns `PermissionDenied`,
+    /// so a second process can safely read a store another process owns.
+    /// Writers take an exclusive lock on the file; read-only stores take
+    /// none.
+    pub read_only: bool,
+    /// Discard any existing contents and start an empty store.
+    pub truncate: bool,
+    /// Budget in bytes for the live records, headers included. Writes that
+    /// would go over it evict keys according to `eviction_policy`. Bulk
+    /// inserts are not checked.
+    pub maxmemory: Option<u64>,
+    pub eviction_policy: EvictionPolicy,
+    /// Checksum for the records of a new file. An existing file keeps the
+    /// one its header names.
+    pub checksum: Checksum,
+    /// The map behind the index. `Ordered` makes `scan_prefix` and
+    /// iterating the index go in key order, at some cost to point lookups.
+    pub index_kind: IndexKind,
+    /// Bytes of appends to hold in memory before writing them to the file
+    /// in one go. 0 writes every record as it comes. Reads see buffered
+    /// records straight away, but a crash of the process loses them until
+    /// `flush` or `sync` has run.
+    pub write_buffer: usize,
+    /// Check each record's checksum when a lookup reads it. Turning this
+    /// off makes reads of large values cheaper and trusts the storage to
+    /// have kept them intact; records are still checksummed when written,
+    /// and loading and `verify` check them regardless.
+    pub verify_on_read: bool,
+    /// Capacity in bytes of the buffer that scans of the whole log (`load`,
+    /// `verify`, `recover`, `find` and compaction) read through. 8 KB by
+    /// default; a larger one, e.g. 1 MB, makes fewer reads, which helps
+    /// where each read is slow. Lookups of a single record read exactly its
+    /// length and don't use it.
+    pub read_buffer: usize,
+}
+
+impl Default for OpenConfig {
+    fn default() -> Self {
+        OpenConfig {
+            create: true,
+            read_only: false,
+            truncate: false,
+            maxmemory: None,
+            eviction_policy: EvictionPolicy::NoEviction,
+            checksum: Checksum::Crc32,
+            index_kind: IndexKind::Hashed,
+            write_buffer: 0,
+            verify_on_read: true,
+            read_buffer: 8 * 1024,
+        }
+    }
 }
 
+/// Identifies a data file written by this crate.
+const MAGIC: &[u8; 4] = b"AKVM";
+/// Version of the on-disk layout. Headerless files are treated as version 1,
+/// version 2 had no expiry field in the record header, version 3 no flags,
+/// version 4 left the record header out of the checksum and version 5 had no
+/// write time in it.
+pub const FORMAT_VERSION: u8 = 6;
+/// Version of the payload layout produced by `AKVMEM::dump`. Version 1 had
+/// no value type.
+const DUMP_VERSION: u8 = 2;
+/// Magic, version byte, checksum algorithm, byte order and a reserved byte.
+/// Records start right after.
+pub const HEADER_LEN: u64 = 8;
+/// Checksum, key and value lengths, expiry, write time and flags that precede
+/// every record's data.
+pub const RECORD_HEADER_LEN: u64 = 4 + 8 + 8 + 8 + 8 + 1;
+
 pub fn open(path: &Path) -> Result<AKVMEM> {
-    let f = OpenOptions::new()
-                                .read(true)
-                                .write(true)
-                                .create(true)
-                                .append(true)
-                                .open(path)?;
+    open_with_options(path, OpenConfig::default())
+}
+
+pub fn open_with_options(path: &Path, config: OpenConfig) -> Result<AKVMEM> {
+    if config.read_only && config.truncate {
+        return Err(Error::new(ErrorKind::InvalidInput, "A read-only store cannot be truncated"))
+    }
+    let mut options = OpenOptions::new();
+    options.read(true);
+    if !config.read_only {
+        options.create(config.create);
+        // Truncation needs plain write access; the write path seeks to the
+        // end before every append, so append mode isn't required for it.
+        if config.truncate {
+            options.write(true).truncate(true);
+        } else {
+            options.append(true);
+        }
+    }
+    let mut f = options.open(path)?;
+    if !config.read_only {
+        lock_exclusive(&f)?;
+        if f.metadata()?.len() == 0 {
+            write_header(&mut f, config.checksum, Endianness::Little)?;
+        }
+    }
+    let (checksum, endianness) = header_format(&f, config.checksum)?;
+    Ok(AKVMEM {
+        f,
+        path: path.to_path_buf(),
+        read_only: config.read_only,
+        dead_bytes: 0,
+        checksum,
+        endianness,
+        maxmemory: config.maxmemory,
+        eviction_policy: config.eviction_policy,
+        unlinked: Vec::new(),
+        pending: Vec::new(),
+        pending_from: 0,
+        write_buffer: config.write_buffer,
+        verify_on_read: config.verify_on_read,
+        read_buffer: config.read_buffer,
+        detached: false,
+        index: Index::new(config.index_kind)
+    })
+}
+
+/// Opens an empty store that keeps its log in memory rather than in a file.
+/// Nothing touches the disk and everything is gone once the store is
+/// dropped, which suits tests and throwaway caches. `path` is empty and
+/// `reopen` only rebuilds the index.
+pub fn open_in_memory() -> Result<AKVMEM<Memory>> {
+    open_storage(Memory::default())
+}
+
+/// Starts a store with default options in `f`, which must be empty, and no
+/// path.
+fn open_storage<S: Storage>(mut f: S) -> Result<AKVMEM<S>> {
+    let config = OpenConfig::default();
+    write_header(&mut f, config.checksum, Endianness::Little)?;
     Ok(AKVMEM {
         f,
-        index: HashMap::new()
+        path: PathBuf::new(),
+        read_only: false,
+        dead_bytes: 0,
+        checksum: config.checksum,
+        endianness: Endianness::Little,
+        maxmemory: config.maxmemory,
+        eviction_policy: config.eviction_policy,
+        unlinked: Vec::new(),
+        pending: Vec::new(),
+        pending_from: 0,
+        write_buffer: config.write_buffer,
+        verify_on_read: config.verify_on_read,
+        read_buffer: config.read_buffer,
+        detached: false,
+        index: Index::new(config.index_kind)
     })
 }
 
-pub fn process_record<R: Read>(f: &mut R) -> Result<KeyValuePair>{
-    let saved_checksum = f.read_u32::<LittleEndian>()?;
+/// The key and value lengths as the record header stores them, or
+/// InvalidInput if they don't fit it.
+fn record_lengths(key_len: usize, val_len: usize) -> Result<(u64, u64)> {
+    let key_len = u64::try_from(key_len)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Key length exceeds the record limit"))?;
+    let val_len = u64::try_from(val_len)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Value length exceeds the record limit"))?;
+    key_len.checked_add(val_len)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Record length exceeds the record limit"))?;
+    Ok((key_len, val_len))
+}
+
+fn write_header<W: Write>(f: &mut W, checksum: Checksum, endianness: Endianness) -> Result<()> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..4].copy_from_slice(MAGIC);
+    header[4] = FORMAT_VERSION;
+    header[5] = checksum.to_byte();
+    header[6] = endianness.to_byte();
+    f.write_all(&header)?;
+    f.flush()
+}
+
+/// The checksum and byte order named by the header of `f`, or `default`
+/// and little-endian if there is no header to go by. `load` rejects such a
+/// file anyway.
+fn header_format<S: Storage>(f: &S, default: Checksum) -> Result<(Checksum, Endianness)> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    match ReadAt::new(f, 0).read_exact(&mut header) {
+        Ok(()) if &header[..4] == MAGIC => Ok((Checksum::from_byte(header[5])?, Endianness::from_byte(header[6])?)),
+        _ => Ok((default, Endianness::Little)),
+    }
+}
+
+/// Checks the header `f` starts with and returns the checksum and byte
+/// order it names.
+fn check_header<R: Read>(f: &mut R) -> Result<(Checksum, Endianness)> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    f.read_exact(&mut header).map_err(|_| {
+        Error::new(ErrorKind::InvalidData, "Data file is too short to hold a header")
+    })?;
+    if &header[..4] != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Data file has no header; it is either not a database or a legacy file that needs migrate_legacy",
+        ))
+    }
+    if header[4] != FORMAT_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported data file version {} (expected {})", header[4], FORMAT_VERSION),
+        ))
+    }
+    Ok((Checksum::from_byte(header[5])?, Endianness::from_byte(header[6])?))
+}
+
+/// Reads the record `f` is at, whose header is in `endianness` byte order,
+/// verifying it with `checksum`.
+pub fn process_record<R: Read>(f: &mut R, checksum: Checksum, endianness: Endianness) -> Result<KeyValuePair>{
+    let header = RecordHeader::read_from(f, endianness)?;
+    read_record_data(f, &header, checksum)
+}
+
+/// Reads a record in the headerless version 1 layout, which used u32 lengths.
+fn process_legacy_record<R: Read>(f: &mut R) -> Result<KeyValuePair>{
+    let checksum = f.read_u32::<LittleEndian>()?;
     let key_len = f.read_u32::<LittleEndian>()?;
     let val_len = f.read_u32::<LittleEndian>()?;
 
-    let data_len = key_len + val_len;
+    let header = RecordHeader { checksum, key_len: key_len as u64, val_len: val_len as u64, expires_at: 0, written_at: 0, flags: 0, endianness: Endianness::Little };
+    // These records only checksummed the key and value.
+    let data = read_data(f, &header, PREALLOC_LIMIT)?;
+    verify_checksum(checksum, crc32::checksum_ieee(&data))?;
+    into_record(&header, data)
+}
+
+/// Reads a file from a fixed offset with positioned reads, which leave the
+/// shared cursor alone and need neither a seek nor a buffer per lookup.
+/// Bytes from `pending_from` on come out of `pending`, the appends a store
+/// is still holding in its write buffer.
+struct ReadAt<'a, S> {
+    f: &'a S,
+    pending: &'a [u8],
+    pending_from: u64,
+    offset: u64,
+}
+
+impl<'a, S: Storage> ReadAt<'a, S> {
+    fn new(f: &'a S, offset: u64) -> Self {
+        ReadAt { f, pending: &[], pending_from: u64::MAX, offset }
+    }
+
+    fn size(&self) -> Result<u64> {
+        if self.pending.is_empty() {
+            return self.f.size()
+        }
+        Ok(self.pending_from + self.pending.len() as u64)
+    }
+}
+
+impl<S: Storage> Read for ReadAt<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = if self.offset >= self.pending_from {
+            let start = usize::try_from(self.offset - self.pending_from).unwrap_or(usize::MAX).min(self.pending.len());
+            let n = buf.len().min(self.pending.len() - start);
+            buf[..n].copy_from_slice(&self.pending[start..start + n]);
+            n
+        } else {
+            let len = buf.len().min(usize::try_from(self.pending_from - self.offset).unwrap_or(usize::MAX));
+            self.f.read_at(&mut buf[..len], self.offset)?
+        };
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Lets a sequential reader such as `load` wrap a `ReadAt` in a `BufReader`
+/// and still jump around.
+impl<S: Storage> Seek for ReadAt<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Seek before the start of the log"))?;
+        Ok(self.offset)
+    }
+}
+
+/// Write buffer `bulk_insert` uses unless told otherwise.
+const BULK_BUFFER_CAPACITY: usize = 1 << 20;
+
+/// Lays out one complete record, header first, so it can go to disk in a
+/// single write.
+fn encode_record(key: &[u8], value: &[u8], expires_at: Option<u64>, written_at: u64, flags: u8, checksum: Checksum, endianness: Endianness) -> Result<ByteString> {
+    if key.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Keys must not be empty"))
+    }
+
+    let (key_len, val_len) = record_lengths(key.len(), value.len())?;
+    let data_len = (key_len + val_len).checked_add(RECORD_HEADER_LEN)
+        .and_then(|len| usize::try_from(len).ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Record length exceeds the record limit"))?;
+
+    let mut record = ByteString::with_capacity(data_len);
+    record.resize(RECORD_HEADER_LEN as usize, 0);
+    // The checksum is filled in last, once everything it covers is laid out.
+    endianness.write_u64(&mut record[4..12], key_len);
+    endianness.write_u64(&mut record[12..20], val_len);
+    endianness.write_u64(&mut record[20..28], expires_at.unwrap_or(0));
+    endianness.write_u64(&mut record[28..36], written_at);
+    record[36] = flags;
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let checksum = checksum.of(&[&record[4..]]);
+    endianness.write_u32(&mut record[..4], checksum);
+    Ok(record)
+}
+
+/// Largest buffer reserved up front for a record's key and value.
+const PREALLOC_LIMIT: u64 = 64 * 1024;
+
+fn read_record_data<R: Read>(f: &mut R, header: &RecordHeader, checksum: Checksum) -> Result<KeyValuePair> {
+    // The lengths come straight off disk, so only trust them for a bounded
+    // preallocation; a damaged header would otherwise abort the process.
+    let data = read_checked(f, header, PREALLOC_LIMIT, checksum)?;
+    into_record(header, data)
+}
+
+/// Splits the key and value `data` of a record into a KeyValuePair.
+fn into_record(header: &RecordHeader, mut data: ByteString) -> Result<KeyValuePair> {
+    let key_len = header.key_len;
+    let value = data.split_off(key_len as usize);
+    data.resize(key_len as usize, 0);
+
+    Ok(KeyValuePair{
+        key: data,
+        value,
+        expires_at: header.expires_at(),
+        value_type: ValueType::from_flags(header.flags)?,
+        tombstone: header.flags & FLAG_TOMBSTONE != 0,
+        written_at: header.written_at,
+    })
+}
+
+/// Reads a record's key and value into one buffer, reserving at most
+/// `prealloc_limit` bytes up front, and checks the record against its
+/// checksum.
+fn read_checked<R: Read>(f: &mut R, header: &RecordHeader, prealloc_limit: u64, checksum: Checksum) -> Result<ByteString> {
+    let data = read_data(f, header, prealloc_limit)?;
+    verify_checksum(header.checksum, header.checksum_of(&data, checksum))?;
+    Ok(data)
+}
+
+/// Reads a record's key and value into one buffer, reserving at most
+/// `prealloc_limit` bytes up front.
+fn read_data<R: Read>(f: &mut R, header: &RecordHeader, prealloc_limit: u64) -> Result<ByteString> {
+    let data_len = header.key_len.checked_add(header.val_len)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Record length overflows u64"))?;
 
-    let mut data = ByteString::with_capacity(data_len as usize);
+    let mut data = ByteString::with_capacity(data_len.min(prealloc_limit) as usize);
 
     {
         f.by_ref()
-        .take(data_len as u64)
+        .take(data_len)
         .read_to_end(&mut data)?;
     }
 
-    debug_assert_eq!(data.len(), data_len as usize);
+    if data.len() as u64 != data_len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Record is truncated"))
+    }
+    Ok(data)
+}
 
-    let checksum = crc32::checksum_ieee(&data);
+fn verify_checksum(saved_checksum: u32, checksum: u32) -> Result<()> {
     if checksum != saved_checksum {
-        panic!("Data corruption detected, saved_checksum -> {:08x} != calculated_checksum -> {:08x}", saved_checksum, checksum)
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Data corruption detected, saved_checksum -> {:08x} != calculated_checksum -> {:08x}", saved_checksum, checksum),
+        ))
     }
-    
-    let value = data.split_off(key_len as usize);
-    data.resize(key_len as usize, 0);
+    Ok(())
+}
+
+/// What `AKVMEM::load_lenient` had to skip to get through a damaged file.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    /// Offset where each damaged region starts.
+    pub damaged_offsets: Vec<u64>,
+    /// Total bytes skipped across all damaged regions.
+    pub skipped_bytes: u64,
+}
+
+/// What `AKVMEM::verify` found checking every record of the data file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Records whose checksum matched, live or not.
+    pub valid_records: u64,
+    /// Offset where each damaged region starts. A region may hold several
+    /// records' worth of bytes; the scan resumes at the next record that
+    /// validates.
+    pub corrupt_offsets: Vec<u64>,
+    /// Total bytes across all damaged regions.
+    pub corrupt_bytes: u64,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_offsets.is_empty()
+    }
+}
+
+/// Offset of the first record after `from` that validates, or `file_len` if
+/// none does, leaving the reader there. Moves between candidates with
+/// relative seeks, which keep the read buffer instead of refilling it for
+/// every byte tried.
+fn resync<R: Read + Seek>(buf: &mut BufReader<R>, from: u64, file_len: u64, checksum: Checksum, endianness: Endianness) -> Result<u64> {
+    let resume = (from + 1..file_len)
+        .find(|&candidate| {
+            buf.stream_position()
+                .and_then(|position| buf.seek_relative(candidate as i64 - position as i64))
+                .is_ok()
+                && record_within(buf, candidate, file_len, true, checksum, endianness).is_ok()
+        })
+        .unwrap_or(file_len);
+    buf.seek(SeekFrom::Start(resume))?;
+    Ok(resume)
+}
+
+/// Reads the record at `position`, which the reader must already be at,
+/// refusing lengths that run past `file_len` so a damaged header can't send
+/// the read off to the end of the file.
+/// When `strict_key` is set an empty key is rejected too, which keeps a run
+/// of zeroed bytes from passing as a stream of valid empty records.
+fn record_within<R: Read>(f: &mut R, position: u64, file_len: u64, strict_key: bool, checksum: Checksum, endianness: Endianness) -> Result<KeyValuePair> {
+    let header = RecordHeader::read_from(f, endianness)?;
+    let fits = header.key_len.checked_add(header.val_len)
+        .and_then(|len| len.checked_add(position + RECORD_HEADER_LEN))
+        .is_some_and(|end| end <= file_len);
+    if !fits {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Record runs past the end of the file"))
+    }
+    if strict_key && header.key_len == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Record has an empty key"))
+    }
+    read_record_data(f, &header, checksum)
+}
+
+/// Copies every record of a headerless (version 1) file at `src` into a new
+/// store at `dst`, in log order, dropping any with an empty key. Version 1
+/// deleted a key by writing it with an empty value, so those records become
+/// tombstones. Returns the number of records copied.
+pub fn migrate_legacy(src: &Path, dst: &Path) -> Result<u64> {
+    let mut r = BufReader::new(File::open(src)?);
+    let mut store = open(dst)?;
+    let mut migrated = 0;
+    loop {
+        let kv = match process_legacy_record(&mut r) {
+            Ok(kv) => kv,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        // Empty keys are no longer accepted, and nothing could read one back.
+        if kv.key.is_empty() {
+            continue;
+        }
+        if kv.value.is_empty() {
+            store.append(&kv.key, &kv.value, None, unix_millis(), FLAG_TOMBSTONE)?;
+        } else {
+            store.insert_ignoring_index(&kv.key, &kv.value)?;
+        }
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+/// Inserts and deletes collected up front and written together by
+/// `AKVMEM::apply_batch`. Later operations on a key win over earlier ones.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(ByteString, Option<ByteString>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn insert(&mut self, key: &ByteString, value: &ByteString) -> &mut Self {
+        self.ops.push((key.clone(), Some(value.clone())));
+        self
+    }
+
+    pub fn delete(&mut self, key: &ByteString) -> &mut Self {
+        self.ops.push((key.clone(), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Writes `records` one after the other through a single buffer and syncs
+/// them.
+fn write_records<S: Storage>(f: &mut S, records: &[ByteString]) -> Result<()> {
+    let mut out = BufWriter::new(&mut *f);
+    for record in records {
+        out.write_all(record)?;
+    }
+    out.flush()?;
+    drop(out);
+    f.sync_data()
+}
+
+/// A compaction in progress, started by `AKVMEM::begin_compaction`.
+///
+/// The live records are copied into a temporary file next to the store by
+/// `copy_live`, which only reads the data file through its own handle and so
+/// can run while the store keeps serving requests. `finish_compaction` then
+/// carries over anything written in the meantime and swaps the files. Other
+/// backends do the same through `Storage::reader`, `scratch` and `replace`.
+pub struct Compaction<S: Storage = File> {
+    src: S,
+    out: BufWriter<S>,
+    /// Only held for its Drop.
+    _scratch: Scratch<S>,
+    positions: Vec<u64>,
+    remap: HashMap<u64, u64>,
+    written: u64,
+    end: u64,
+    dead_at_start: u64,
+    endianness: Endianness,
+    read_buffer: usize,
+}
+
+impl<S: Storage> Compaction<S> {
+    pub fn copy_live(&mut self) -> Result<()> {
+        let mut src = BufReader::with_capacity(self.read_buffer, ReadAt::new(&self.src, 0));
+        let mut current = 0;
+        for &position in &self.positions {
+            // Positions are sorted, so this only ever skips forward and keeps
+            // the read buffer when the next record is already in it.
+            src.seek_relative((position - current) as i64)?;
+            let mut header = [0u8; RECORD_HEADER_LEN as usize];
+            src.read_exact(&mut header)?;
+            let data_len = self.endianness.decode_u64(&header[4..12]) + self.endianness.decode_u64(&header[12..20]);
+            self.out.write_all(&header)?;
+            let copied = io::copy(&mut src.by_ref().take(data_len), &mut self.out)?;
+            if copied != data_len {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Record is truncated"))
+            }
+            self.remap.insert(position, self.written);
+            self.written += RECORD_HEADER_LEN + data_len;
+            current = position + RECORD_HEADER_LEN + data_len;
+        }
+        self.out.flush()
+    }
+}
 
-    Ok(KeyValuePair{key: data, value})
+/// Hands the scratch storage of a compaction back to `discard_scratch` once
+/// the compaction is dropped, however it ended.
+struct Scratch<S: Storage> {
+    path: PathBuf,
+    storage: PhantomData<S>,
 }
 
-impl AKVMEM {
+impl<S: Storage> Drop for Scratch<S> {
+    fn drop(&mut self) {
+        S::discard_scratch(&self.path);
+    }
+}
+
+impl<S: Storage> AKVMEM<S> {
+    /// Rebuilds the index by replaying the log from the start. A later
+    /// record for a key replaces an earlier one; a tombstone or an expired
+    /// record removes the key, so deleted keys stay deleted across restarts.
+    /// Fails on the first record that doesn't validate.
     pub fn load(&mut self) -> Result<()>{
-        let mut buf = BufReader::new(&self.f);
+        self.replay(false).map(|_| ())
+    }
 
-        loop {
-            let current_position = buf.seek(SeekFrom::Current(0))?;
+    /// Like `load`, but instead of failing on a damaged record it scans
+    /// forward byte by byte until a record validates again and carries on
+    /// from there. The report says what was skipped.
+    pub fn load_lenient(&mut self) -> Result<LoadReport> {
+        self.replay(true)
+    }
+
+    /// Throws the index away and loads it again from the log, as a restart
+    /// would. If the log doesn't load, the store is left as it was and the
+    /// error returned.
+    pub fn reload(&mut self) -> Result<()> {
+        self.flush()?;
+        let empty = self.index.empty_like(self.index.len());
+        let index = mem::replace(&mut self.index, empty);
+        let dead_bytes = self.dead_bytes;
+        if let Err(err) = self.load() {
+            self.index = index;
+            self.dead_bytes = dead_bytes;
+            return Err(err)
+        }
+        Ok(())
+    }
 
-            let maybe_kv = process_record(&mut buf);
+    fn replay(&mut self, lenient: bool) -> Result<LoadReport> {
+        self.flush_pending()?;
+        let file_len = self.file_size()?;
+        let mut buf = BufReader::with_capacity(self.read_buffer, ReadAt::new(&self.f, 0));
+        let (checksum, endianness) = check_header(&mut buf)?;
+        self.checksum = checksum;
+        self.endianness = endianness;
 
-            let kv = match maybe_kv {
+        // Sizes of the records currently in the index, by offset, so a
+        // superseded record can be counted as dead without re-reading it.
+        let mut sizes = HashMap::<u64, u64>::new();
+        let mut report = LoadReport::default();
+        self.dead_bytes = 0;
+        let now = unix_millis();
+        let mut current_position = HEADER_LEN;
+
+        while current_position < file_len {
+            let kv = match record_within(&mut buf, current_position, file_len, false, self.checksum, self.endianness) {
                 Ok(kv) => kv,
-                Err(err) => {
+                Err(err) if !lenient => {
                     if err.kind() == ErrorKind::UnexpectedEof {
                         break;
                     }
@@ -92,91 +969,1083 @@ impl AKVMEM {
                         return Err(err)
                     }
                 }
+                Err(_) => {
+                    let resume = resync(&mut buf, current_position, file_len, self.checksum, self.endianness)?;
+                    tracing::warn!(offset = current_position, skipped = resume - current_position, "skipping corrupt record");
+                    report.damaged_offsets.push(current_position);
+                    report.skipped_bytes += resume - current_position;
+                    self.dead_bytes += resume - current_position;
+                    current_position = resume;
+                    continue;
+                }
             };
 
-            self.index.insert(kv.key, current_position);
+            let size = buf.stream_position()? - current_position;
+            // Tombstones and expired records still mask whatever was written
+            // before them, but are never live themselves.
+            let old_position = if kv.tombstone || kv.is_expired(now) {
+                self.dead_bytes += size;
+                self.index.remove(&kv.key)
+            } else {
+                sizes.insert(current_position, size);
+                self.index.insert(kv.key, IndexEntry::new(current_position, size, kv.written_at, now))
+            };
+            if let Some(old) = old_position {
+                self.dead_bytes += sizes.remove(&old.position).unwrap_or(0);
+            }
+            current_position += size;
         }
-        Ok(())
+        Ok(report)
     }
-    pub fn seek_to_end(&mut self) -> u64 {
-        self.f.seek(SeekFrom::End(0)).unwrap()
+
+    /// Checks the checksum of every record in the file, carrying on past
+    /// damaged ones the way `load_lenient` does, and reports what it found.
+    /// Neither the file nor the index is touched, so this is safe to run
+    /// before deciding between `recover` and restoring a backup. A record
+    /// cut short at the end of the file counts as damage.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let file_len = self.file_size()?;
+        let mut buf = BufReader::with_capacity(self.read_buffer, self.reader_at(0));
+        let (checksum, endianness) = check_header(&mut buf)?;
+        let mut report = VerifyReport::default();
+        let mut current_position = HEADER_LEN;
+        while current_position < file_len {
+            match record_within(&mut buf, current_position, file_len, false, checksum, endianness) {
+                Ok(_) => {
+                    report.valid_records += 1;
+                    current_position = buf.stream_position()?;
+                }
+                Err(_) => {
+                    let resume = resync(&mut buf, current_position, file_len, checksum, endianness)?;
+                    report.corrupt_offsets.push(current_position);
+                    report.corrupt_bytes += resume - current_position;
+                    current_position = resume;
+                }
+            }
+        }
+        Ok(report)
     }
 
-    pub fn insert_ignoring_index(&mut self, key: &ByteString, value: &ByteString) -> Result<u64> {
-        let mut f = BufWriter::new(&mut self.f);
-        let key = key.to_vec();
-        let value = value.to_vec();
+    /// Repairs a file with trailing garbage, e.g. a record cut short by a
+    /// crash. Validates records from the start, truncates the file at the
+    /// first one that fails and rebuilds the index from what is left.
+    /// Returns the number of bytes cut off. Unlike `load_lenient` nothing
+    /// past a damaged record is kept.
+    pub fn recover(&mut self) -> Result<u64> {
+        self.check_writable()?;
+        self.flush()?;
+        let file_len = self.file_size()?;
+        let mut valid_end = HEADER_LEN;
+        {
+            let mut buf = BufReader::with_capacity(self.read_buffer, ReadAt::new(&self.f, 0));
+            let (checksum, endianness) = check_header(&mut buf)?;
+            while valid_end < file_len {
+                if record_within(&mut buf, valid_end, file_len, true, checksum, endianness).is_err() {
+                    break;
+                }
+                valid_end = buf.stream_position()?;
+            }
+        }
+        if valid_end < file_len {
+            tracing::warn!(offset = valid_end, truncated = file_len - valid_end, "truncating damaged tail");
+            self.f.set_len(valid_end)?;
+        }
+        self.index.clear();
+        self.load()?;
+        Ok(file_len - valid_end)
+    }
 
-        let mut tmp = Vec::<u8>::with_capacity(key.len() + value.len());
-        tmp.extend(key.iter());
-        tmp.extend(value.iter());
-    
-        let checksum = crc32::checksum_ieee(&tmp);
+    /// Bytes taken up by superseded and deleted records, which a compaction
+    /// would reclaim.
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead_bytes
+    }
 
-        let next_byte = SeekFrom::End(0);
+    /// Fraction of the record area of the file that is dead, from 0.0 to 1.0.
+    pub fn dead_ratio(&self) -> Result<f64> {
+        let records = self.file_size()?.saturating_sub(HEADER_LEN);
+        if records == 0 {
+            return Ok(0.0)
+        }
+        Ok(self.dead_bytes as f64 / records as f64)
+    }
 
-        let current_position = f.seek(SeekFrom::Current(0))?;
+    fn header_at(&self, position: u64) -> Result<RecordHeader> {
+        let mut raw = [0; RECORD_HEADER_LEN as usize];
+        self.reader_at(position).read_exact(&mut raw)?;
+        RecordHeader::read_from(&mut &raw[..], self.endianness)
+    }
 
-        f.seek(next_byte)?;
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key.len()  as u32)?;
-        f.write_u32::<LittleEndian>(value.len() as u32)?;
-        f.write_all(&tmp)?;
-        f.flush()?;
-        Ok(current_position)
+    /// Position of the live record for `key`, skipping one that has expired.
+    fn live_header(&self, key: &ByteString) -> Result<Option<(u64, RecordHeader)>> {
+        let position = match self.index.get(key) {
+            Some(entry) => entry.position,
+            None => return Ok(None),
+        };
+        let header = self.header_at(position)?;
+        match header.expires_at() {
+            Some(expires_at) if expires_at <= unix_millis() => Ok(None),
+            _ => Ok(Some((position, header))),
+        }
     }
 
-    pub fn get(&self, key: &ByteString) -> Result<Option<ByteString>> {
-        let kv = {
-            if let Some(position) = self.index.get(key) {
-             Some(self.get_at(*position)?.value)
+    /// Like `live_header`, but fails with a WRONGTYPE error when the key holds
+    /// a hash or list, for the commands that read string values in place.
+    fn live_string_header(&self, key: &ByteString) -> Result<Option<(u64, RecordHeader)>> {
+        match self.live_header(key)? {
+            Some((_, header)) if ValueType::from_flags(header.flags)? != ValueType::String => {
+                Err(collections::wrong_type())
             }
-            else  { None }
-        };
-        Ok(kv)
+            live => Ok(live),
+        }
     }
-    
-    pub fn get_at(&self, position: u64) -> Result<KeyValuePair> {
-        let mut buf = BufReader::new(&self.f);
-        buf.seek(SeekFrom::Start(position))?;
-        let kv = process_record(&mut buf)?;
-        Ok(kv)
+
+    /// Snapshots the live records and creates the file they get copied into.
+    pub fn begin_compaction(&self) -> Result<Compaction<S>> {
+        // A compaction reads the log back through `path`, which must still
+        // be this one.
+        self.check_writable()?;
+        // Records still in the write buffer aren't in the file `reader`
+        // opens, so they are left to `finish_compaction` with the rest of
+        // the tail.
+        let end = self.f.size()?;
+        let mut positions: Vec<u64> = self.index.values()
+            .map(|entry| entry.position)
+            .filter(|&position| position < end)
+            .collect();
+        positions.sort_unstable();
+
+        let scratch = Scratch { path: self.path.clone(), storage: PhantomData };
+        let mut out = BufWriter::new(S::scratch(&self.path)?);
+        // Records are copied as they are, so the new file keeps the byte
+        // order of the old one.
+        write_header(&mut out, self.checksum, self.endianness)?;
+
+        Ok(Compaction {
+            src: self.f.reader(&self.path, end)?,
+            out,
+            _scratch: scratch,
+            positions,
+            remap: HashMap::new(),
+            written: HEADER_LEN,
+            end,
+            dead_at_start: self.dead_bytes,
+            endianness: self.endianness,
+            read_buffer: self.read_buffer,
+        })
     }
 
-    pub fn insert(&mut self, key: &ByteString, value: &ByteString) -> Result<()>{
-        let current_position = self.insert_ignoring_index(key, value)?;
-        self.index.insert(key.clone(), current_position);
+    /// Appends whatever was written since `begin_compaction`, then replaces
+    /// the data file with the compacted one and points the index at it.
+    pub fn finish_compaction(&mut self, mut compaction: Compaction<S>) -> Result<()> {
+        // The tail is copied verbatim, so its records keep their offsets
+        // relative to each other.
+        self.flush_pending()?;
+        let tail_start = compaction.written;
+        io::copy(&mut ReadAt::new(&self.f, compaction.end), &mut compaction.out)?;
+        compaction.out.flush()?;
+        compaction.out.get_ref().sync_all()?;
+
+        let mut index = self.index.empty_like(self.index.len());
+        for (key, entry) in &self.index {
+            let position = entry.position;
+            let new_position = if position >= compaction.end {
+                position - compaction.end + tail_start
+            } else {
+                *compaction.remap.get(&position).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "Compaction lost track of a live record")
+                })?
+            };
+            index.insert(key.clone(), IndexEntry::new(new_position, entry.len, entry.written_at, entry.last_access()));
+        }
+
+        let compacted = compaction.out.into_inner().map_err(|err| err.into_error())?;
+        self.f.replace(compacted, &self.path)?;
+        self.index = index;
+        // Records that died after the snapshot were copied along with the
+        // live ones, so only what was dead at the start has been reclaimed.
+        self.dead_bytes = self.dead_bytes.saturating_sub(compaction.dead_at_start);
+        tracing::info!(reclaimed = compaction.dead_at_start, keys = self.index.len(), "compaction finished");
         Ok(())
     }
 
-    #[inline]
-    pub fn update(&mut self, key: &ByteString, value: &ByteString) -> Result<()>{
-        self.insert(key, value)
+    /// Rewrites the data file with only the live records.
+    pub fn compact(&mut self) -> Result<()> {
+        let mut compaction = self.begin_compaction()?;
+        compaction.copy_live()?;
+        self.finish_compaction(compaction)
     }
 
-    #[inline]
-    pub fn delete(&mut self, key: &ByteString) -> Result<()>{
-        self.index.remove(key);
-        self.insert(key, b"".to_vec().as_ref())
+    /// Flushes the data file and its metadata to disk with `fsync`.
+    ///
+    /// Without a write buffer, writes are handed to the OS before `insert`,
+    /// `update` and `delete` return, so they survive a crash of this
+    /// process, but only a sync makes them survive a power loss.
+    /// `finish_compaction` syncs the new file before swapping it in; nothing
+    /// else syncs on its own.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.f.sync_all()
     }
 
-    pub fn find(&mut self, target: &ByteString) -> Result<Option<(u64, ByteString)>> {
-        let mut r = BufReader::new(&self.f);
-        r.seek(SeekFrom::Start(0))?;
-        loop {
-            let current_position = r.seek(SeekFrom::Current(0))?;
+    /// Like `sync` but skips metadata that isn't needed to read the data
+    /// back, such as the modification time. Usually cheaper.
+    pub fn sync_data(&mut self) -> Result<()> {
+        self.flush()?;
+        self.f.sync_data()
+    }
 
-            let maybe_kv = process_record(&mut r);
+    /// Hands everything the store is holding back, unlinked tombstones and
+    /// the write buffer, to the OS without syncing it.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_unlinked()?;
+        self.flush_pending()
+    }
 
-            match maybe_kv {
-                Ok(kv) => {
-                    if kv.value == *target {
-                        if self.index.values().collect::<Vec<&u64>>().iter().any(|pos| **pos == current_position) {
-                            return Ok(Some((current_position, kv.value)))
-                        }
-                    }
-                }
-                Err(err) => {
+    /// Writes out the write buffer. On failure the file is cut back to
+    /// where the buffer starts and the buffer is kept, so a later flush
+    /// writes it again in full.
+    fn flush_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(())
+        }
+        self.f.seek_end()?;
+        if let Err(err) = self.f.write_all(&self.pending) {
+            let _ = self.f.set_len(self.pending_from);
+            return Err(err)
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Reads the log from `offset` on, write buffer included.
+    fn reader_at(&self, offset: u64) -> ReadAt<'_, S> {
+        let pending_from = if self.pending.is_empty() { u64::MAX } else { self.pending_from };
+        ReadAt { f: &self.f, pending: &self.pending, pending_from, offset }
+    }
+
+    /// Copies the data file as it stands to `dest` and syncs the copy, for
+    /// backing up a live store. The log is only ever appended to, so the
+    /// bytes up to the current end are a complete store of their own even
+    /// if writes carry on once the caller lets go of the store. Tombstones
+    /// `unlink` queued are written first, so unlinked keys stay gone in the
+    /// copy.
+    pub fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        self.write_unlinked()?;
+        let end = self.file_size()?;
+        let mut out = BufWriter::new(File::create(dest)?);
+        let copied = io::copy(&mut self.reader_at(0).take(end), &mut out)?;
+        if copied != end {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Data file shrank during the snapshot"))
+        }
+        out.flush()?;
+        out.get_ref().sync_all()
+    }
+
+    /// Preallocates room for `bytes` more of log ahead of a big load, such
+    /// as a `bulk_insert` of an import, so the file isn't grown and
+    /// fragmented one append at a time. The file's length doesn't change.
+    /// Uses `fallocate` on Linux and does nothing on other platforms. Call
+    /// `release_reserved` once the load is done to give back what it didn't
+    /// use.
+    pub fn reserve(&mut self, bytes: u64) -> Result<()> {
+        self.check_writable()?;
+        self.f.reserve(bytes)
+    }
+
+    /// Frees space preallocated by `reserve` that the log hasn't grown into.
+    pub fn release_reserved(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.f.release_reserved()
+    }
+
+    /// Size of the data file in bytes, header included, counting what is
+    /// still in the write buffer.
+    pub fn file_size(&self) -> Result<u64> {
+        self.reader_at(0).size()
+    }
+
+    /// Path of the data file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the data file at `path` again and rebuilds the index from it,
+    /// for when the file has been swapped out from under the store, e.g. by
+    /// an external compaction or log rotation. A replacement that is empty
+    /// gets a fresh header unless the store is read-only.
+    pub fn reopen(&mut self) -> Result<()> {
+        self.flush()?;
+        self.f.reopen(&self.path, !self.read_only)?;
+        self.detached = false;
+        if !self.read_only && self.f.size()? == 0 {
+            write_header(&mut self.f, self.checksum, Endianness::Little)?;
+        }
+        let (checksum, endianness) = header_format(&self.f, self.checksum)?;
+        self.checksum = checksum;
+        self.endianness = endianness;
+        self.index.clear();
+        self.load()
+    }
+
+    /// Writes out everything held back, as `flush` does, and returns where
+    /// the log ends.
+    pub fn seek_to_end(&mut self) -> Result<u64> {
+        self.flush()?;
+        self.f.seek_end()
+    }
+
+    pub fn insert_ignoring_index(&mut self, key: &ByteString, value: &ByteString) -> Result<u64> {
+        self.append(key, value, None, unix_millis(), 0)
+    }
+
+    /// Appends a record without touching the index. `expires_at` is a Unix
+    /// time in milliseconds.
+    fn append(&mut self, key: &ByteString, value: &ByteString, expires_at: Option<u64>, written_at: u64, flags: u8) -> Result<u64> {
+        self.check_writable()?;
+        self.write_unlinked()?;
+        let record = encode_record(key, value, expires_at, written_at, flags, self.checksum, self.endianness)?;
+        if self.write_buffer > 0 {
+            if self.pending.is_empty() {
+                self.pending_from = self.f.seek_end()?;
+            }
+            let position = self.pending_from + self.pending.len() as u64;
+            self.pending.extend_from_slice(&record);
+            if self.pending.len() >= self.write_buffer {
+                self.flush_pending()?;
+            }
+            return Ok(position)
+        }
+        let current_position = self.f.seek_end()?;
+        // write_all already retries interrupted and short writes. If it
+        // still fails part way, cut the file back so that the next load
+        // does not find a half written record at the end.
+        if let Err(err) = self.f.write_all(&record) {
+            let _ = self.f.set_len(current_position);
+            return Err(err)
+        }
+        Ok(current_position)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Store was opened read-only"))
+        }
+        if self.detached {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Data file {} was deleted or replaced while open; writes are refused until it is restored", self.path.display()),
+            ))
+        }
+        Ok(())
+    }
+
+    /// Checks the data file is still at `path`. Once it has been deleted or
+    /// replaced, e.g. by a misconfigured log rotation, appends would only
+    /// reach a file nothing can open again, so from then on writes fail
+    /// with NotFound until `restore_file` or `reopen` has run. Reads carry
+    /// on from the open file, which still holds everything. Returns whether
+    /// the file was there.
+    pub fn check_file(&mut self) -> Result<bool> {
+        self.detached = !self.f.is_at(&self.path)?;
+        Ok(!self.detached)
+    }
+
+    /// Writes the open log back to `path` after it was deleted from there,
+    /// then carries on with the new copy. Refuses with AlreadyExists if
+    /// something else has taken the path; `reopen` switches to that.
+    pub fn restore_file(&mut self) -> Result<()> {
+        if self.path.exists() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} has been replaced; reopen to use the new file", self.path.display()),
+            ))
+        }
+        self.flush()?;
+        let path = self.path.clone();
+        self.snapshot(&path)?;
+        self.reopen()
+    }
+
+    /// Inserts every pair through one buffer that is flushed once at the
+    /// end, instead of flushing per record like `insert`. If any pair fails
+    /// the file is cut back to where it was, so either every pair is stored
+    /// or none is.
+    pub fn bulk_insert<I: Iterator<Item = (ByteString, ByteString)>>(&mut self, pairs: I) -> Result<()> {
+        self.bulk_insert_with_capacity(BULK_BUFFER_CAPACITY, pairs)
+    }
+
+    /// `bulk_insert` with a write buffer of `capacity` bytes.
+    pub fn bulk_insert_with_capacity<I: Iterator<Item = (ByteString, ByteString)>>(&mut self, capacity: usize, pairs: I) -> Result<()> {
+        self.check_writable()?;
+        self.flush()?;
+        let start = self.f.seek_end()?;
+        let mut written = Vec::new();
+        let (checksum, endianness) = (self.checksum, self.endianness);
+        let now = unix_millis();
+        let result = (|| {
+            let mut f = BufWriter::with_capacity(capacity, &mut self.f);
+            let mut position = start;
+            for (key, value) in pairs {
+                let record = encode_record(&key, &value, None, now, ValueType::String.to_flags(), checksum, endianness)?;
+                f.write_all(&record)?;
+                written.push((key, position, record.len() as u64));
+                position += record.len() as u64;
+            }
+            f.flush()
+        })();
+        if let Err(err) = result {
+            let _ = self.f.set_len(start);
+            return Err(err)
+        }
+        for (key, position, len) in written {
+            if let Some(old) = self.index.insert(key, IndexEntry::new(position, len, now, now)) {
+                self.dead_bytes += old.len;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &ByteString) -> Result<Option<ByteString>> {
+        Ok(self.get_record(key)?.map(|kv| kv.value))
+    }
+
+    /// The value under `key` along with what type of value it is. The value
+    /// is a view into the one buffer the record was read into, so it can go
+    /// out on a socket without being copied again.
+    pub fn get_typed(&self, key: &ByteString) -> Result<Option<(ValueType, Bytes)>> {
+        let (header, record) = match self.read_live(key)? {
+            Some(live) => live,
+            None => return Ok(None),
+        };
+        let data = Bytes::from(record);
+        Ok(Some((ValueType::from_flags(header.flags)?, data.slice((RECORD_HEADER_LEN + header.key_len) as usize..))))
+    }
+
+    /// The live record for `key`, or None if it is missing or has expired.
+    fn get_record(&self, key: &ByteString) -> Result<Option<KeyValuePair>> {
+        match self.read_live(key)? {
+            Some((header, mut record)) => {
+                record.drain(..RECORD_HEADER_LEN as usize);
+                Ok(Some(into_record(&header, record)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the whole live record for `key`, header included, with a single
+    /// positioned read of the length the index has for it, and checks it.
+    /// None if the key is missing or has expired; otherwise the key counts
+    /// as just used.
+    fn read_live(&self, key: &ByteString) -> Result<Option<(RecordHeader, ByteString)>> {
+        let entry = match self.index.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let len = usize::try_from(entry.len)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Record length exceeds the address space"))?;
+        let mut record = vec![0; len];
+        self.reader_at(entry.position).read_exact(&mut record)?;
+        let header = RecordHeader::read_from(&mut &record[..], self.endianness)?;
+        if header.record_len() != entry.len {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No record of the indexed length at position {}", entry.position)))
+        }
+        if self.verify_on_read {
+            verify_checksum(header.checksum, header.checksum_of(&record[RECORD_HEADER_LEN as usize..], self.checksum))?;
+        }
+        let now = unix_millis();
+        if header.expires_at().is_some_and(|expires_at| expires_at <= now) {
+            return Ok(None)
+        }
+        entry.last_access.set(now);
+        Ok(Some((header, record)))
+    }
+
+    /// Marks `key` as just used, for LRU eviction.
+    fn touch(&self, key: &ByteString) {
+        if let Some(entry) = self.index.get(key) {
+            entry.last_access.set(unix_millis());
+        }
+    }
+    
+    /// Whether `key` holds a value that hasn't expired.
+    pub fn exists(&self, key: &ByteString) -> Result<bool> {
+        Ok(self.live_header(key)?.is_some())
+    }
+
+    /// Unix time in milliseconds `key` was last written at, or None if it
+    /// isn't live. Compaction keeps the time of the original write.
+    pub fn written_at(&self, key: &ByteString) -> Result<Option<u64>> {
+        if self.live_header(key)?.is_none() {
+            return Ok(None)
+        }
+        Ok(Some(self.index[key.as_slice()].written_at))
+    }
+
+    /// When `key` expires, as a Unix time in milliseconds. None if the key
+    /// isn't live, Some(None) if it never expires.
+    pub fn expiry(&self, key: &ByteString) -> Result<Option<Option<u64>>> {
+        Ok(self.live_header(key)?.map(|(_, header)| header.expires_at()))
+    }
+
+    /// Milliseconds since `key` was last read or written, or None if it
+    /// isn't live. Asking doesn't count as a use.
+    pub fn idle_time(&self, key: &ByteString) -> Result<Option<u64>> {
+        if self.live_header(key)?.is_none() {
+            return Ok(None)
+        }
+        let last_access = self.index[key.as_slice()].last_access();
+        Ok(Some(unix_millis().saturating_sub(last_access)))
+    }
+
+    /// Marks each of `keys` that is live as just used, without reading its
+    /// value. Returns how many were.
+    pub fn touch_keys(&self, keys: &[ByteString]) -> Result<usize> {
+        let mut touched = 0;
+        for key in keys {
+            if self.live_header(key)?.is_some() {
+                self.touch(key);
+                touched += 1;
+            }
+        }
+        Ok(touched)
+    }
+
+    /// An arbitrary live key, or None if there are none. Keys that have
+    /// expired but are still in the index are passed over.
+    pub fn random_key(&self) -> Result<Option<ByteString>> {
+        if self.index.is_empty() {
+            return Ok(None)
+        }
+        let start = rand::thread_rng().gen_range(0..self.index.len());
+        for key in self.index.keys().cycle().skip(start).take(self.index.len()) {
+            if self.live_header(key)?.is_some() {
+                return Ok(Some(key.clone()))
+            }
+        }
+        Ok(None)
+    }
+
+    /// The live keys that start with `prefix`. They come back in key order
+    /// when the index is ordered, which then only visits the matching keys;
+    /// a hashed index is walked in full and its keys come back unordered.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<ByteString>> {
+        let mut keys = Vec::new();
+        for (key, _) in self.index.prefix(prefix) {
+            if self.live_header(key)?.is_some() {
+                keys.push(key.clone());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Length of the string stored under `key`, read from its record header.
+    pub fn value_len(&self, key: &ByteString) -> Result<Option<u64>> {
+        Ok(self.live_string_header(key)?.map(|(_, header)| header.val_len))
+    }
+
+    /// Reads up to `len` bytes of the value under `key`, starting at byte
+    /// `start`, without loading the rest of the record. The range is clamped
+    /// to the value. The checksum covers the whole record, so the bytes
+    /// returned here are not verified.
+    pub fn get_range(&self, key: &ByteString, start: u64, len: u64) -> Result<Option<ByteString>> {
+        let (position, header) = match self.live_string_header(key)? {
+            Some(live) => live,
+            None => return Ok(None),
+        };
+        self.touch(key);
+        let (key_len, val_len) = (header.key_len, header.val_len);
+        let start = start.min(val_len);
+        let len = len.min(val_len - start);
+
+        let mut data = ByteString::new();
+        self.reader_at(position + RECORD_HEADER_LEN + key_len + start).take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Record is truncated"))
+        }
+        Ok(Some(data))
+    }
+
+    /// Serializes the record for `key` so it can be moved to another store.
+    ///
+    /// The payload is the bincode encoded record (key, value and expiry)
+    /// followed by a version byte and a CRC32 of everything before it, which
+    /// lets the receiving side validate it before inserting anything.
+    pub fn dump(&self, key: &ByteString) -> Result<Option<ByteString>> {
+        let position = match self.index.get(key) {
+            Some(entry) => entry.position,
+            None => return Ok(None),
+        };
+        let kv = self.get_at(position)?;
+        if kv.is_expired(unix_millis()) {
+            return Ok(None)
+        }
+        let mut payload = bincode::serialize(&kv)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        payload.push(DUMP_VERSION);
+        let checksum = crc32::checksum_ieee(&payload);
+        payload.write_u32::<LittleEndian>(checksum)?;
+        Ok(Some(payload))
+    }
+
+    /// Inserts a record produced by `dump` under `key`, keeping its expiry.
+    /// Fails with `AlreadyExists` if `key` is live unless `replace` is set,
+    /// and with `InvalidData` if the payload doesn't validate.
+    pub fn restore(&mut self, key: &ByteString, payload: &[u8], replace: bool) -> Result<()> {
+        if payload.len() < 5 {
+            return Err(Error::new(ErrorKind::InvalidData, "DUMP payload is too short"))
+        }
+        let (body, checksum) = payload.split_at(payload.len() - 4);
+        if crc32::checksum_ieee(body) != LittleEndian::read_u32(checksum) {
+            return Err(Error::new(ErrorKind::InvalidData, "DUMP payload checksum mismatch"))
+        }
+        let (record, version) = body.split_at(body.len() - 1);
+        if version[0] != DUMP_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported DUMP payload version {}", version[0]),
+            ))
+        }
+        let kv: KeyValuePair = bincode::deserialize(record)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+
+        if !replace && self.live_header(key)?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "BUSYKEY Target key name already exists."))
+        }
+        self.put(key, &kv.value, kv.expires_at, kv.value_type)?;
+        Ok(())
+    }
+
+    /// Reads the record starting at `position`. An offset outside the data
+    /// file is rejected with InvalidInput; one that is inside but not at a
+    /// record boundary almost always fails the length or checksum checks
+    /// with InvalidData. The checksum is skipped if the store was opened
+    /// without `verify_on_read`.
+    pub fn get_at(&self, position: u64) -> Result<KeyValuePair> {
+        let file_len = self.file_size()?;
+        if position < HEADER_LEN || position.saturating_add(RECORD_HEADER_LEN) > file_len {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Position {} is outside the data file", position)))
+        }
+        let header = self.header_at(position)?;
+        if header.key_len.checked_add(header.val_len).and_then(|len| len.checked_add(position + RECORD_HEADER_LEN))
+            .is_none_or(|end| end > file_len) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No complete record at position {}", position)))
+        }
+        let mut reader = self.reader_at(position + RECORD_HEADER_LEN);
+        if !self.verify_on_read {
+            return into_record(&header, read_data(&mut reader, &header, PREALLOC_LIMIT)?)
+        }
+        read_record_data(&mut reader, &header, self.checksum)
+    }
+
+    /// Stores `value` under `key` and returns the offset its record starts at.
+    pub fn insert(&mut self, key: &ByteString, value: &ByteString) -> Result<u64>{
+        self.insert_with_expiry(key, value, None)
+    }
+
+    /// Like `insert`, but the value stops being visible once the Unix time
+    /// in milliseconds reaches `expires_at`.
+    pub fn insert_with_expiry(&mut self, key: &ByteString, value: &ByteString, expires_at: Option<u64>) -> Result<u64>{
+        self.put(key, value, expires_at, ValueType::String)
+    }
+
+    /// Writes a live record, points the index at it and returns its offset.
+    fn put(&mut self, key: &ByteString, value: &ByteString, expires_at: Option<u64>, value_type: ValueType) -> Result<u64>{
+        let len = RECORD_HEADER_LEN + key.len() as u64 + value.len() as u64;
+        self.make_room(key, len)?;
+        let now = unix_millis();
+        let current_position = self.append(key, value, expires_at, now, value_type.to_flags())?;
+        if let Some(old) = self.index.insert(key.clone(), IndexEntry::new(current_position, len, now, now)) {
+            self.dead_bytes += old.len;
+        }
+        Ok(current_position)
+    }
+
+    /// Bytes taken up by live records, headers included. This is what
+    /// `maxmemory` limits.
+    pub fn used_memory(&self) -> Result<u64> {
+        Ok(self.file_size()?.saturating_sub(HEADER_LEN + self.dead_bytes))
+    }
+
+    /// Evicts keys other than `key` until a record of `record_len` bytes
+    /// written under `key` fits in `maxmemory`, or fails with OutOfMemory if
+    /// the policy doesn't allow it or nothing is left to evict.
+    fn make_room(&mut self, key: &ByteString, record_len: u64) -> Result<()> {
+        for victim in self.eviction_victims(key, record_len)? {
+            tracing::debug!(key = %String::from_utf8_lossy(&victim), "evicting key");
+            self.delete(&victim)?;
+        }
+        Ok(())
+    }
+
+    /// The keys `make_room` would evict, in order, without evicting them.
+    fn eviction_victims(&self, key: &ByteString, record_len: u64) -> Result<Vec<ByteString>> {
+        let limit = match self.maxmemory {
+            Some(limit) => limit,
+            None => return Ok(Vec::new()),
+        };
+        // Overwriting a key frees its old record once the new one is in.
+        let replaced = match self.index.get(key) {
+            Some(entry) => entry.len,
+            None => 0,
+        };
+        let mut used = self.used_memory()?;
+        let mut victims: Vec<ByteString> = Vec::new();
+        while used.saturating_add(record_len).saturating_sub(replaced) > limit {
+            let candidates = self.index.iter().filter(|(candidate, _)| *candidate != key && !victims.contains(candidate));
+            let victim = match self.eviction_policy {
+                EvictionPolicy::NoEviction => None,
+                EvictionPolicy::AllKeysRandom => candidates.choose(&mut rand::thread_rng()),
+                EvictionPolicy::AllKeysLru => candidates.min_by_key(|(_, entry)| entry.last_access()),
+            };
+            let (victim, entry) = match victim {
+                Some(victim) => victim,
+                None => return Err(Error::new(
+                    ErrorKind::OutOfMemory,
+                    "OOM command not allowed when used memory > 'maxmemory'.",
+                )),
+            };
+            // Deleting a key frees its record; the tombstone is dead bytes.
+            used = used.saturating_sub(entry.len);
+            victims.push(victim.clone());
+        }
+        Ok(victims)
+    }
+
+    #[inline]
+    pub fn update(&mut self, key: &ByteString, value: &ByteString) -> Result<u64>{
+        self.insert(key, value)
+    }
+
+    /// Writes `new` under `key` only if the current value is `expected`,
+    /// keeping the key's expiry. Callers holding `&mut self` get the compare
+    /// and the write as one step. Returns whether the value was swapped.
+    pub fn compare_and_swap(&mut self, key: &ByteString, expected: &ByteString, new: &ByteString) -> Result<bool> {
+        match self.get_record(key)? {
+            Some(kv) if kv.value_type != ValueType::String => Err(collections::wrong_type()),
+            Some(kv) if kv.value == *expected => {
+                self.put(key, new, kv.expires_at, ValueType::String)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes `value` under `key` as SET with `options` does, replacing a
+    /// value of any type. Returns whether it wrote anything, which NX or XX
+    /// can rule out, and with GET the string the key held before. GET on a
+    /// key holding another type fails with WRONGTYPE and writes nothing.
+    pub fn set_with_options(
+        &mut self,
+        key: &ByteString,
+        value: &ByteString,
+        options: &server_helpers::SetOptions,
+    ) -> Result<(bool, Option<ByteString>)> {
+        let current = self.get_record(key)?;
+        let previous = match &current {
+            Some(kv) if options.get && kv.value_type != ValueType::String => return Err(collections::wrong_type()),
+            Some(kv) if options.get => Some(kv.value.clone()),
+            _ => None,
+        };
+        if (options.only_if_absent && current.is_some()) || (options.only_if_present && current.is_none()) {
+            return Ok((false, previous))
+        }
+        let expires_at = match options.ttl {
+            Some(ttl) => Some(unix_millis().checked_add(ttl).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "invalid expire time in 'set' command")
+            })?),
+            None if options.keep_ttl => current.and_then(|kv| kv.expires_at),
+            None => None,
+        };
+        self.put(key, value, expires_at, ValueType::String)?;
+        Ok((true, previous))
+    }
+
+    /// Deletes `key` and returns the string it held, or None if it was
+    /// missing. Callers holding `&mut self` get the read and the delete as
+    /// one step, so only one of them sees the value.
+    pub fn get_del(&mut self, key: &ByteString) -> Result<Option<ByteString>> {
+        match self.get_record(key)? {
+            Some(kv) if kv.value_type != ValueType::String => Err(collections::wrong_type()),
+            Some(kv) => {
+                self.delete(key)?;
+                Ok(Some(kv.value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Overwrites the string under `key` with `value` starting at byte
+    /// `offset`, zero-padding a shorter value up to `offset` first and
+    /// keeping the key's expiry. A missing key counts as empty. Returns the
+    /// length of the value afterwards.
+    pub fn set_range(&mut self, key: &ByteString, offset: u64, value: &ByteString) -> Result<u64> {
+        let kv = self.get_record(key)?;
+        let (mut current, expires_at) = match kv {
+            Some(kv) if kv.value_type != ValueType::String => return Err(collections::wrong_type()),
+            Some(kv) => (kv.value, kv.expires_at),
+            None => (ByteString::new(), None),
+        };
+        if value.is_empty() {
+            // Nothing to write, and a missing key isn't created for it.
+            return Ok(current.len() as u64)
+        }
+        let end = offset.checked_add(value.len() as u64)
+            .filter(|&end| end <= server_helpers::MAX_BULK_LEN as u64)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "string exceeds maximum allowed size (proto-max-bulk-len)"))?;
+        let (offset, end) = (offset as usize, end as usize);
+        if current.len() < end {
+            current.resize(end, 0);
+        }
+        current[offset..end].copy_from_slice(value);
+        self.put(key, &current, expires_at, ValueType::String)?;
+        Ok(current.len() as u64)
+    }
+
+    /// Adds `delta` to the integer stored as a string under `key`, keeping
+    /// the key's expiry, and returns the result. A missing key counts as 0.
+    /// Fails with InvalidData if the value isn't a base 10 integer that fits
+    /// an i64 or the result would overflow, writing nothing.
+    pub fn incr_by(&mut self, key: &ByteString, delta: i64) -> Result<i64> {
+        let (current, expires_at) = match self.get_record(key)? {
+            Some(kv) if kv.value_type != ValueType::String => return Err(collections::wrong_type()),
+            Some(kv) => {
+                let current = std::str::from_utf8(&kv.value).ok()
+                    .filter(|digits| !digits.starts_with('+'))
+                    .and_then(|digits| digits.parse::<i64>().ok())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "value is not an integer or out of range"))?;
+                (current, kv.expires_at)
+            }
+            None => (0, None),
+        };
+        let value = current.checked_add(delta)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "increment or decrement would overflow"))?;
+        self.put(key, &value.to_string().into_bytes(), expires_at, ValueType::String)?;
+        Ok(value)
+    }
+
+    /// Adds `delta` to the number stored as a string under `key`, keeping the
+    /// key's expiry, and returns the result as it was stored. A missing key
+    /// counts as 0. The result is written in the shortest form that parses
+    /// back to the same f64, without an exponent or trailing zeros, so
+    /// repeated increments don't pile up digits. Fails with InvalidData if
+    /// the value isn't a finite number or the result wouldn't be, writing
+    /// nothing.
+    pub fn incr_by_float(&mut self, key: &ByteString, delta: f64) -> Result<ByteString> {
+        let (current, expires_at) = match self.get_record(key)? {
+            Some(kv) if kv.value_type != ValueType::String => return Err(collections::wrong_type()),
+            Some(kv) => {
+                let current = std::str::from_utf8(&kv.value).ok()
+                    .filter(|digits| !digits.starts_with(char::is_whitespace) && !digits.ends_with(char::is_whitespace))
+                    .and_then(|digits| digits.parse::<f64>().ok())
+                    .filter(|current| current.is_finite())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "value is not a valid float"))?;
+                (current, kv.expires_at)
+            }
+            None => (0.0, None),
+        };
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(Error::new(ErrorKind::InvalidData, "increment would produce NaN or Infinity"))
+        }
+        // Adding 0.0 turns -0 into 0, which is how redis writes it.
+        let value = (value + 0.0).to_string().into_bytes();
+        self.put(key, &value, expires_at, ValueType::String)?;
+        Ok(value)
+    }
+
+    /// Copies the value under `src` to `dst`, keeping its type and expiry.
+    /// Returns false without writing anything if `src` is missing, or if
+    /// `dst` exists and `replace` isn't set. Copying a key onto itself is
+    /// an InvalidInput error.
+    pub fn copy(&mut self, src: &ByteString, dst: &ByteString, replace: bool) -> Result<bool> {
+        if src == dst {
+            return Err(Error::new(ErrorKind::InvalidInput, "source and destination objects are the same"))
+        }
+        let kv = match self.get_record(src)? {
+            Some(kv) => kv,
+            None => return Ok(false),
+        };
+        if !replace && self.live_header(dst)?.is_some() {
+            return Ok(false)
+        }
+        self.put(dst, &kv.value, kv.expires_at, kv.value_type)?;
+        Ok(true)
+    }
+
+    /// Rewrites the record under `key` with a new expiry, or none at all.
+    /// An expiry already in the past deletes the key. Returns false if the
+    /// key does not exist.
+    pub fn set_expiry(&mut self, key: &ByteString, expires_at: Option<u64>) -> Result<bool> {
+        let kv = match self.get_record(key)? {
+            Some(kv) => kv,
+            None => return Ok(false),
+        };
+        match expires_at {
+            Some(at) if at <= unix_millis() => self.delete(key)?,
+            _ => {
+                self.put(key, &kv.value, expires_at, kv.value_type)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes the expiry from `key`. Returns false if the key does not
+    /// exist or has no expiry to remove.
+    pub fn persist(&mut self, key: &ByteString) -> Result<bool> {
+        match self.get_record(key)? {
+            Some(kv) if kv.expires_at.is_some() => self.set_expiry(key, None),
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes every operation in `batch` with one flush and one fsync, then
+    /// points the index at all of them in one go, so a caller sharing the
+    /// store never sees part of a batch. If anything fails the file is cut
+    /// back and the index is left as it was. Keys evicted to make room for
+    /// the batch are deleted by tombstones written ahead of it in the same
+    /// write, so they are only gone if the batch went in. A crash part way
+    /// through the write can still leave a prefix of the batch on disk.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        self.write_unlinked()?;
+        if batch.is_empty() {
+            return Ok(())
+        }
+        let now = unix_millis();
+        let mut ops = Vec::with_capacity(batch.len());
+        for (key, value) in &batch.ops {
+            ops.push(match value {
+                Some(value) => encode_record(key, value, None, now, ValueType::String.to_flags(), self.checksum, self.endianness)?,
+                None => encode_record(key, b"", None, now, FLAG_TOMBSTONE, self.checksum, self.endianness)?,
+            });
+        }
+        let inserted = batch.ops.iter().zip(&ops)
+            .filter(|((_, value), _)| value.is_some())
+            .map(|(_, record)| record.len() as u64)
+            .sum();
+        // Nothing is credited for keys the batch overwrites, so this may
+        // evict a little more than strictly needed.
+        let victims = self.eviction_victims(&ByteString::new(), inserted)?;
+        let mut records = victims.iter()
+            .map(|victim| encode_record(victim, b"", None, now, FLAG_TOMBSTONE, self.checksum, self.endianness))
+            .collect::<Result<Vec<_>>>()?;
+        let evictions_len: u64 = records.iter().map(|record| record.len() as u64).sum();
+        records.extend(ops);
+        let ops = &records[victims.len()..];
+
+        // Work out what the batch leaves dead before writing, so a failed
+        // read can't happen once the records are in the file.
+        let mut dead = evictions_len;
+        let mut latest: HashMap<&[u8], Option<u64>> = HashMap::new();
+        for victim in &victims {
+            dead += self.index.get(victim).map_or(0, |entry| entry.len);
+            latest.insert(victim, None);
+        }
+        for ((key, value), record) in batch.ops.iter().zip(ops) {
+            let replaced = match latest.get(key.as_slice()) {
+                Some(len) => *len,
+                None => self.index.get(key).map(|entry| entry.len),
+            };
+            dead += replaced.unwrap_or(0);
+            let len = record.len() as u64;
+            if value.is_some() {
+                latest.insert(key, Some(len));
+            } else {
+                dead += len;
+                latest.insert(key, None);
+            }
+        }
+
+        self.flush_pending()?;
+        let start = self.f.seek_end()?;
+        if let Err(err) = write_records(&mut self.f, &records) {
+            let _ = self.f.set_len(start);
+            return Err(err)
+        }
+        for victim in &victims {
+            tracing::debug!(key = %String::from_utf8_lossy(victim), "evicting key");
+            self.index.remove(victim);
+        }
+        let mut position = start + evictions_len;
+        for ((key, value), record) in batch.ops.into_iter().zip(ops) {
+            if value.is_some() {
+                self.index.insert(key, IndexEntry::new(position, record.len() as u64, now, now));
+            } else {
+                self.index.remove(&key);
+            }
+            position += record.len() as u64;
+        }
+        self.dead_bytes += dead;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &ByteString) -> Result<()>{
+        self.append(key, b"".to_vec().as_ref(), None, unix_millis(), FLAG_TOMBSTONE)?;
+        // The tombstone only exists to mask older records, so it is dead
+        // from the moment it is written.
+        self.dead_bytes += RECORD_HEADER_LEN + key.len() as u64;
+        if let Some(old) = self.index.remove(key) {
+            self.dead_bytes += old.len;
+        }
+        Ok(())
+    }
+
+    /// Deletes `keys` from the index straight away but leaves their
+    /// tombstones to `write_unlinked`, so deleting many keys costs no I/O up
+    /// front. Returns how many of them were live. Reads stop seeing the keys
+    /// at once; the tombstones are written, in order, before any other write
+    /// or sync, and when the store is dropped.
+    pub fn unlink(&mut self, keys: &[ByteString]) -> Result<usize> {
+        self.check_writable()?;
+        let mut removed = 0;
+        for key in keys {
+            if self.live_header(key)?.is_some() {
+                removed += 1;
+            }
+            if let Some(old) = self.index.remove(key) {
+                self.dead_bytes += old.len;
+                self.unlinked.push(key.clone());
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Appends the tombstones of keys `unlink` removed, if any are waiting.
+    /// On failure they stay queued and the file is cut back.
+    pub fn write_unlinked(&mut self) -> Result<()> {
+        if self.unlinked.is_empty() {
+            return Ok(())
+        }
+        let now = unix_millis();
+        let records = self.unlinked.iter()
+            .map(|key| encode_record(key, b"", None, now, FLAG_TOMBSTONE, self.checksum, self.endianness))
+            .collect::<Result<Vec<_>>>()?;
+        self.flush_pending()?;
+        let start = self.f.seek_end()?;
+        if let Err(err) = write_records(&mut self.f, &records) {
+            let _ = self.f.set_len(start);
+            return Err(err)
+        }
+        self.dead_bytes += records.iter().map(|record| record.len() as u64).sum::<u64>();
+        self.unlinked.clear();
+        Ok(())
+    }
+
+    pub fn find(&mut self, target: &ByteString) -> Result<Option<(u64, ByteString)>> {
+        let mut r = BufReader::with_capacity(self.read_buffer, self.reader_at(HEADER_LEN));
+        loop {
+            let current_position = r.stream_position()?;
+
+            let maybe_kv = process_record(&mut r, self.checksum, self.endianness);
+
+            match maybe_kv {
+                Ok(kv) => {
+                    if !kv.tombstone && kv.value == *target && self.index.values().any(|entry| entry.position == current_position) {
+                        return Ok(Some((current_position, kv.value)))
+                    }
+                }
+                Err(err) => {
                     match err.kind() {
                         ErrorKind::UnexpectedEof => break,
                         _ => return Err(Error::new(ErrorKind::NotFound, "Unexpected error while searching database"))
@@ -189,4 +2058,945 @@ impl AKVMEM {
         Ok(None)
 
     }
-}
\ No newline at end of file
+}
+
+impl<S: Storage> Drop for AKVMEM<S> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            tracing::error!("Unable to write unlinked tombstones or buffered writes: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A data file of its own for each test, removed once it is dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("libactionkv-{}-{}", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// A log in memory that takes only `budget` more bytes before its writes
+    /// fail, like a disk filling up part way through a record.
+    #[derive(Default)]
+    struct FailingWrites {
+        log: Memory,
+        budget: Option<usize>,
+    }
+
+    impl Write for FailingWrites {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let n = match self.budget {
+                Some(0) => return Err(Error::other("No space left on device")),
+                Some(budget) => buf.len().min(budget),
+                None => buf.len(),
+            };
+            self.budget = self.budget.map(|budget| budget - n);
+            self.log.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.log.flush()
+        }
+    }
+
+    impl Storage for FailingWrites {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.log.read_at(buf, offset)
+        }
+
+        fn size(&self) -> Result<u64> {
+            self.log.size()
+        }
+
+        fn seek_end(&mut self) -> Result<u64> {
+            self.log.seek_end()
+        }
+
+        fn set_len(&mut self, len: u64) -> Result<()> {
+            self.log.set_len(len)
+        }
+
+        fn sync_all(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn sync_data(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reader(&self, path: &Path, end: u64) -> Result<Self> {
+            Ok(FailingWrites { log: self.log.reader(path, end)?, budget: None })
+        }
+
+        fn scratch(_path: &Path) -> Result<Self> {
+            Ok(FailingWrites::default())
+        }
+
+        fn replace(&mut self, compacted: Self, path: &Path) -> Result<()> {
+            self.log.replace(compacted.log, path)
+        }
+
+        fn discard_scratch(_path: &Path) {}
+
+        fn reopen(&mut self, _path: &Path, _writable: bool) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_lengths_past_u32_are_kept_whole() {
+        // One byte more than a u32 length holds, which a u32 field would
+        // have recorded as a 1 byte value.
+        let len = u32::MAX as u64 + 2;
+        assert_eq!(record_lengths(3, len as usize).unwrap(), (3, len));
+        let mut record = Vec::new();
+        record.write_u32::<LittleEndian>(crc32::checksum_ieee(b"keyv")).unwrap();
+        record.write_u64::<LittleEndian>(3).unwrap();
+        record.write_u64::<LittleEndian>(len).unwrap();
+        record.extend_from_slice(b"keyv");
+        // Only one byte of value follows, so the read fails rather than
+        // handing back a short value.
+        assert_eq!(process_record(&mut &record[..], Checksum::Crc32, Endianness::Little).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn keys_deleted_in_a_legacy_file_stay_deleted_after_migrating() {
+        let (src, dst) = (TempFile::new("legacy-src"), TempFile::new("legacy-dst"));
+        let mut legacy = Vec::new();
+        for (key, value) in [(&b"kept"[..], &b"1"[..]), (b"gone", b"2"), (b"gone", b"")] {
+            let data = [key, value].concat();
+            legacy.write_u32::<LittleEndian>(crc32::checksum_ieee(&data)).unwrap();
+            legacy.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+            legacy.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+            legacy.extend_from_slice(&data);
+        }
+        fs::write(&src.0, &legacy).unwrap();
+
+        assert_eq!(migrate_legacy(&src.0, &dst.0).unwrap(), 3);
+        let mut store = open(&dst.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(&b"gone".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn lengths_past_the_record_limit_are_rejected() {
+        // No slice that long can be allocated, so the check is driven with
+        // the lengths alone.
+        for (key_len, val_len) in [(usize::MAX, 1), (1, usize::MAX)] {
+            assert_eq!(record_lengths(key_len, val_len).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn values_round_trip_through_the_log() {
+        let file = TempFile::new("round-trip");
+        let mut store = open(&file.0).unwrap();
+        let lens = [0, 1, 65535, 65536, 65537];
+        for (n, &len) in lens.iter().enumerate() {
+            let key = format!("key{}", n).into_bytes();
+            let position = store.insert_ignoring_index(&key, &vec![0xab; len]).unwrap();
+            let len = RECORD_HEADER_LEN + key.len() as u64 + len as u64;
+            store.index.insert(key, IndexEntry::new(position, len, unix_millis(), unix_millis()));
+        }
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        for (n, &len) in lens.iter().enumerate() {
+            assert_eq!(store.get(&format!("key{}", n).into_bytes()).unwrap(), Some(vec![0xab; len]));
+        }
+    }
+
+    #[test]
+    fn empty_keys_are_rejected() {
+        let file = TempFile::new("empty-key");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let len = store.file_size().unwrap();
+        for inserted in [store.insert(&b"".to_vec(), &b"value".to_vec()).map(|_| ()), store.insert_ignoring_index(&b"".to_vec(), &b"".to_vec()).map(|_| ())] {
+            assert_eq!(inserted.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(store.file_size().unwrap(), len);
+        assert!(store.index.is_empty());
+    }
+
+    #[test]
+    fn tombstones_and_empty_values_reload_apart() {
+        let file = TempFile::new("tombstone");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"deleted".to_vec(), &b"value".to_vec()).unwrap();
+        store.delete(&b"deleted".to_vec()).unwrap();
+        store.insert(&b"empty".to_vec(), &b"".to_vec()).unwrap();
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"deleted".to_vec()).unwrap(), None);
+        assert_eq!(store.get(&b"empty".to_vec()).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn deleted_keys_stay_deleted_across_a_restart() {
+        let file = TempFile::new("restart-delete");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+        store.delete(&b"key".to_vec()).unwrap();
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"key".to_vec()).unwrap(), None);
+        assert!(!store.index.contains_key(b"key".as_slice()));
+    }
+
+    #[test]
+    fn a_failed_bulk_insert_leaves_the_file_as_it_was() {
+        let file = TempFile::new("bulk-failure");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"kept".to_vec(), &b"value".to_vec()).unwrap();
+        let len = store.file_size().unwrap();
+        // A buffer smaller than one record sends each record to the file as
+        // it is written, so the first pair is on disk when the empty key
+        // fails.
+        let pairs = vec![(b"first".to_vec(), vec![0xab; 64]), (Vec::new(), b"value".to_vec())];
+        let err = store.bulk_insert_with_capacity(16, pairs.into_iter()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(store.file_size().unwrap(), len);
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(&b"first".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn a_second_writer_is_locked_out() {
+        let file = TempFile::new("lock");
+        let store = open(&file.0).unwrap();
+        assert_eq!(open(&file.0).err().map(|err| err.kind()), Some(ErrorKind::WouldBlock));
+        let read_only = OpenConfig { read_only: true, ..OpenConfig::default() };
+        assert!(open_with_options(&file.0, read_only).is_ok());
+        drop(store);
+        assert!(open(&file.0).is_ok());
+    }
+
+    #[test]
+    fn copy_only_overwrites_with_replace() {
+        let file = TempFile::new("copy");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let (src, dst) = (b"src".to_vec(), b"dst".to_vec());
+        assert!(!store.copy(&src, &dst, false).unwrap());
+        store.insert(&src, &b"one".to_vec()).unwrap();
+        store.insert(&dst, &b"two".to_vec()).unwrap();
+        assert!(!store.copy(&src, &dst, false).unwrap());
+        assert_eq!(store.get(&dst).unwrap(), Some(b"two".to_vec()));
+        assert!(store.copy(&src, &dst, true).unwrap());
+        assert_eq!(store.get(&dst).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(store.get(&src).unwrap(), Some(b"one".to_vec()));
+
+        let len = store.file_size().unwrap();
+        for replace in [false, true] {
+            assert_eq!(store.copy(&src, &src, replace).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(store.file_size().unwrap(), len);
+    }
+
+    #[test]
+    fn lru_eviction_keeps_recently_read_keys() {
+        let file = TempFile::new("lru");
+        let record_len = RECORD_HEADER_LEN + 2 + 5;
+        let config = OpenConfig {
+            maxmemory: Some(2 * record_len),
+            eviction_policy: EvictionPolicy::AllKeysLru,
+            ..OpenConfig::default()
+        };
+        let mut store = open_with_options(&file.0, config).unwrap();
+        store.load().unwrap();
+        store.insert(&b"k1".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"k2".to_vec(), &b"value".to_vec()).unwrap();
+        // Both keys were likely written in the same millisecond, so age k2
+        // by hand; the read keeps k1 recent.
+        store.index[b"k2".as_slice()].last_access.set(0);
+        store.get(&b"k1".to_vec()).unwrap();
+        store.insert(&b"k3".to_vec(), &b"value".to_vec()).unwrap();
+        assert!(store.index.contains_key(b"k1".as_slice()));
+        assert!(!store.index.contains_key(b"k2".as_slice()));
+        assert_eq!(store.used_memory().unwrap(), 2 * record_len);
+    }
+
+    #[test]
+    fn a_snapshot_opens_as_the_store_it_was_taken_from() {
+        let file = TempFile::new("snapshot-src");
+        let copy = TempFile::new("snapshot-dest");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"kept".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"unlinked".to_vec(), &b"value".to_vec()).unwrap();
+        store.unlink(&[b"unlinked".to_vec()]).unwrap();
+        store.snapshot(&copy.0).unwrap();
+        store.insert(&b"later".to_vec(), &b"value".to_vec()).unwrap();
+
+        let mut snapshot = open(&copy.0).unwrap();
+        snapshot.load().unwrap();
+        assert_eq!(snapshot.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(snapshot.get(&b"later".to_vec()).unwrap(), None);
+        assert_eq!(snapshot.get(&b"unlinked".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn a_batch_is_applied_whole_or_not_at_all() {
+        let file = TempFile::new("batch");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"old".to_vec(), &b"value".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.insert(&b"new".to_vec(), &b"first".to_vec())
+            .insert(&b"new".to_vec(), &b"second".to_vec())
+            .delete(&b"old".to_vec());
+        store.apply_batch(batch).unwrap();
+        assert_eq!(store.get(&b"new".to_vec()).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(store.get(&b"old".to_vec()).unwrap(), None);
+        let used = store.used_memory().unwrap();
+        assert_eq!(used, RECORD_HEADER_LEN + 3 + 6);
+
+        // The empty key is refused, which takes the valid insert down with it.
+        let len = store.file_size().unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(&b"other".to_vec(), &b"value".to_vec()).delete(&Vec::new());
+        assert_eq!(store.apply_batch(batch).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(store.file_size().unwrap(), len);
+        assert_eq!(store.get(&b"other".to_vec()).unwrap(), None);
+
+        drop(store);
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"new".to_vec()).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(store.used_memory().unwrap(), used);
+    }
+
+    #[test]
+    fn a_write_failing_mid_record_leaves_no_trace() {
+        let mut store = open_storage(FailingWrites::default()).unwrap();
+        store.insert(&b"kept".to_vec(), &b"value".to_vec()).unwrap();
+        let end = store.f.size().unwrap();
+
+        store.f.budget = Some(RECORD_HEADER_LEN as usize + 2);
+        assert!(store.insert(&b"lost".to_vec(), &b"value".to_vec()).is_err());
+        assert_eq!(store.f.size().unwrap(), end);
+
+        store.f.budget = None;
+        store.index.clear();
+        store.load().unwrap();
+        assert!(store.verify().unwrap().corrupt_offsets.is_empty());
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(&b"lost".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn a_failed_batch_evicts_nothing() {
+        let mut store = open_storage(FailingWrites::default()).unwrap();
+        store.maxmemory = Some(2 * (RECORD_HEADER_LEN + 2 + 5));
+        store.eviction_policy = EvictionPolicy::AllKeysLru;
+        store.insert(&b"k1".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"k2".to_vec(), &b"value".to_vec()).unwrap();
+        let (end, used) = (store.f.size().unwrap(), store.used_memory().unwrap());
+
+        let mut batch = WriteBatch::new();
+        batch.insert(&b"k3".to_vec(), &b"value".to_vec());
+        store.f.budget = Some(RECORD_HEADER_LEN as usize + 2);
+        assert!(store.apply_batch(batch).is_err());
+        assert_eq!((store.f.size().unwrap(), store.used_memory().unwrap()), (end, used));
+        // Looked up without a read, which would spare it from LRU eviction.
+        assert!(store.index.contains_key(b"k1".as_slice()));
+
+        let mut batch = WriteBatch::new();
+        batch.insert(&b"k3".to_vec(), &b"value".to_vec());
+        store.f.budget = None;
+        store.apply_batch(batch).unwrap();
+        assert_eq!(store.used_memory().unwrap(), used);
+        // k1 and k2 can share a last access time, so either may have gone.
+        store.index.clear();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(&b"k3".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn an_in_memory_store_needs_no_file() {
+        let mut store = open_in_memory().unwrap();
+        store.load().unwrap();
+        store.insert(&b"kept".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"gone".to_vec(), &b"value".to_vec()).unwrap();
+        store.delete(&b"gone".to_vec()).unwrap();
+        store.compact().unwrap();
+
+        assert_eq!(store.dead_bytes(), 0);
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(&b"gone".to_vec()).unwrap(), None);
+        store.reopen().unwrap();
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.path(), Path::new(""));
+    }
+
+    #[test]
+    fn reserving_space_leaves_the_log_as_it_was() {
+        let file = TempFile::new("reserve");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let len = store.file_size().unwrap();
+        store.reserve(1 << 20).unwrap();
+        assert_eq!(store.file_size().unwrap(), len);
+
+        store.bulk_insert((0..100u32).map(|n| (n.to_be_bytes().to_vec(), vec![0xab; 100]))).unwrap();
+        store.release_reserved().unwrap();
+        drop(store);
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 100);
+    }
+
+    #[test]
+    fn reads_skip_the_checksum_without_verify_on_read() {
+        for verify_on_read in [true, false] {
+            let file = TempFile::new(&format!("verify-on-read-{}", verify_on_read));
+            let config = OpenConfig { verify_on_read, ..OpenConfig::default() };
+            let mut store = open_with_options(&file.0, config).unwrap();
+            store.load().unwrap();
+            let position = store.insert(&b"k".to_vec(), &b"value".to_vec()).unwrap();
+
+            let mut f = OpenOptions::new().write(true).open(&file.0).unwrap();
+            f.seek(SeekFrom::Start(position + RECORD_HEADER_LEN + 5)).unwrap();
+            f.write_all(b"X").unwrap();
+            if verify_on_read {
+                assert_eq!(store.get(&b"k".to_vec()).unwrap_err().kind(), ErrorKind::InvalidData);
+                assert_eq!(store.get_at(position).unwrap_err().kind(), ErrorKind::InvalidData);
+            } else {
+                assert_eq!(store.get(&b"k".to_vec()).unwrap(), Some(b"valuX".to_vec()));
+                assert_eq!(store.get_at(position).unwrap().value, b"valuX".to_vec());
+            }
+            assert_eq!(store.verify().unwrap().corrupt_offsets, vec![position]);
+        }
+    }
+
+    #[test]
+    fn reload_rebuilds_the_index_or_leaves_it_alone() {
+        let file = TempFile::new("reload");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"kept".to_vec(), &b"value".to_vec()).unwrap();
+        let damaged = store.insert(&b"gone".to_vec(), &b"value".to_vec()).unwrap();
+        store.delete(&b"gone".to_vec()).unwrap();
+        store.reload().unwrap();
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(&b"gone".to_vec()).unwrap(), None);
+
+        let dead_bytes = store.dead_bytes();
+        let mut f = OpenOptions::new().write(true).open(&file.0).unwrap();
+        f.seek(SeekFrom::Start(damaged + RECORD_HEADER_LEN)).unwrap();
+        f.write_all(b"X").unwrap();
+        assert_eq!(store.reload().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.index.len(), 1);
+        assert_eq!(store.dead_bytes(), dead_bytes);
+    }
+
+    #[test]
+    fn a_big_endian_file_is_read_and_written_in_its_own_byte_order() {
+        let file = TempFile::new("big-endian");
+        write_header(&mut File::create(&file.0).unwrap(), Checksum::Crc32, Endianness::Big).unwrap();
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let position = store.insert(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"other".to_vec(), &b"value".to_vec()).unwrap();
+        store.delete(&b"other".to_vec()).unwrap();
+
+        let raw = fs::read(&file.0).unwrap();
+        assert_eq!(BigEndian::read_u64(&raw[position as usize + 4..]), 3);
+        store.compact().unwrap();
+        assert!(store.verify().unwrap().is_clean());
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(&b"other".to_vec()).unwrap(), None);
+        assert_eq!(fs::read(&file.0).unwrap()[6], Endianness::Big.to_byte());
+    }
+
+    #[test]
+    fn scans_work_through_a_read_buffer_smaller_than_a_record() {
+        let file = TempFile::new("read-buffer");
+        let config = OpenConfig { read_buffer: 4, ..OpenConfig::default() };
+        let mut store = open_with_options(&file.0, config).unwrap();
+        store.load().unwrap();
+        store.insert(&b"first".to_vec(), &b"value".to_vec()).unwrap();
+        let damaged = store.insert(&b"second".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"third".to_vec(), &b"value".to_vec()).unwrap();
+
+        let mut f = OpenOptions::new().write(true).open(&file.0).unwrap();
+        f.seek(SeekFrom::Start(damaged + RECORD_HEADER_LEN)).unwrap();
+        f.write_all(b"X").unwrap();
+        store.index.clear();
+        let report = store.load_lenient().unwrap();
+        assert_eq!(report.damaged_offsets, vec![damaged]);
+        assert_eq!(store.get(&b"third".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.verify().unwrap().corrupt_offsets, vec![damaged]);
+    }
+
+    #[test]
+    fn writes_stop_once_the_data_file_is_deleted_until_it_is_restored() {
+        let file = TempFile::new("deleted");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"before".to_vec(), &b"value".to_vec()).unwrap();
+        assert!(store.check_file().unwrap());
+
+        fs::remove_file(&file.0).unwrap();
+        assert!(!store.check_file().unwrap());
+        assert_eq!(store.insert(&b"after".to_vec(), &b"value".to_vec()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(store.get(&b"before".to_vec()).unwrap(), Some(b"value".to_vec()));
+
+        store.restore_file().unwrap();
+        assert!(store.check_file().unwrap());
+        store.insert(&b"after".to_vec(), &b"value".to_vec()).unwrap();
+        drop(store);
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+
+        fs::remove_file(&file.0).unwrap();
+        fs::write(&file.0, b"someone else's").unwrap();
+        assert!(!store.check_file().unwrap());
+        assert_eq!(store.restore_file().unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn verify_reports_damage_without_touching_the_store() {
+        let file = TempFile::new("verify");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"first".to_vec(), &b"value".to_vec()).unwrap();
+        let damaged = store.insert(&b"second".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"third".to_vec(), &b"value".to_vec()).unwrap();
+        assert_eq!(store.verify().unwrap(), VerifyReport { valid_records: 3, ..VerifyReport::default() });
+
+        let len = store.file_size().unwrap();
+        let mut f = OpenOptions::new().write(true).open(&file.0).unwrap();
+        f.seek(SeekFrom::Start(damaged + RECORD_HEADER_LEN)).unwrap();
+        f.write_all(b"X").unwrap();
+        let report = store.verify().unwrap();
+        assert_eq!(report.valid_records, 2);
+        assert_eq!(report.corrupt_offsets, vec![damaged]);
+        assert_eq!(report.corrupt_bytes, RECORD_HEADER_LEN + 6 + 5);
+        assert_eq!(store.file_size().unwrap(), len);
+        assert_eq!(store.get(&b"third".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn touch_counts_live_keys_and_bumps_them() {
+        let file = TempFile::new("touch");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"k1".to_vec(), &b"value".to_vec()).unwrap();
+        store.index[b"k1".as_slice()].last_access.set(0);
+
+        assert_eq!(store.touch_keys(&[b"k1".to_vec(), b"missing".to_vec()]).unwrap(), 1);
+        assert!(store.index[b"k1".as_slice()].last_access() > 0);
+    }
+
+    #[test]
+    fn a_500ms_expiry_is_reported_and_then_honoured() {
+        let mut store = open_in_memory().unwrap();
+        let expires_at = unix_millis() + 500;
+        store.insert_with_expiry(&b"k".to_vec(), &b"v".to_vec(), Some(expires_at)).unwrap();
+        store.insert(&b"forever".to_vec(), &b"v".to_vec()).unwrap();
+        assert_eq!(store.expiry(&b"k".to_vec()).unwrap(), Some(Some(expires_at)));
+        assert_eq!(store.expiry(&b"forever".to_vec()).unwrap(), Some(None));
+
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        assert_eq!(store.get(&b"k".to_vec()).unwrap(), None);
+        assert_eq!(store.expiry(&b"k".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn idle_time_counts_from_the_last_access_without_bumping_it() {
+        let file = TempFile::new("idletime");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"k1".to_vec(), &b"value".to_vec()).unwrap();
+        let last_access = unix_millis() - 5_000;
+        store.index[b"k1".as_slice()].last_access.set(last_access);
+
+        assert!(store.idle_time(&b"k1".to_vec()).unwrap().unwrap() >= 5_000);
+        assert_eq!(store.index[b"k1".as_slice()].last_access(), last_access);
+        assert_eq!(store.idle_time(&b"missing".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn buffered_writes_reach_the_file_on_flush_or_when_the_buffer_fills() {
+        let file = TempFile::new("write-buffer");
+        let config = OpenConfig { write_buffer: 4096, ..OpenConfig::default() };
+        let mut store = open_with_options(&file.0, config).unwrap();
+        store.load().unwrap();
+        store.insert(&b"k1".to_vec(), &b"v1".to_vec()).unwrap();
+        assert_eq!(fs::metadata(&file.0).unwrap().len(), HEADER_LEN);
+        assert!(store.file_size().unwrap() > HEADER_LEN);
+        store.flush().unwrap();
+        assert_eq!(fs::metadata(&file.0).unwrap().len(), store.file_size().unwrap());
+
+        store.insert(&b"k2".to_vec(), &vec![b'x'; 4096]).unwrap();
+        assert_eq!(fs::metadata(&file.0).unwrap().len(), store.file_size().unwrap());
+
+        // A compaction started with records still buffered carries them over.
+        store.insert(&b"k1".to_vec(), &b"v2".to_vec()).unwrap();
+        let mut compaction = store.begin_compaction().unwrap();
+        compaction.copy_live().unwrap();
+        store.insert(&b"k3".to_vec(), &b"v3".to_vec()).unwrap();
+        store.finish_compaction(compaction).unwrap();
+        store.delete(&b"k2".to_vec()).unwrap();
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"k1".to_vec()).unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(store.get(&b"k2".to_vec()).unwrap(), None);
+        assert_eq!(store.get(&b"k3".to_vec()).unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn a_key_reads_back_as_soon_as_it_is_written() {
+        for write_buffer in [0, 1 << 20] {
+            let file = TempFile::new(&format!("read-your-writes-{}", write_buffer));
+            let config = OpenConfig { write_buffer, ..OpenConfig::default() };
+            let mut store = open_with_options(&file.0, config).unwrap();
+            store.load().unwrap();
+            for i in 0..100u32 {
+                let key = format!("key:{}", i).into_bytes();
+                let value = i.to_string().repeat(i as usize).into_bytes();
+                store.insert(&key, &value).unwrap();
+                assert_eq!(store.get(&key).unwrap(), Some(value.clone()));
+                assert_eq!(store.get_range(&key, 0, 1).unwrap(), Some(value[..value.len().min(1)].to_vec()));
+            }
+            store.update(&b"key:1".to_vec(), &b"updated".to_vec()).unwrap();
+            assert_eq!(store.get(&b"key:1".to_vec()).unwrap(), Some(b"updated".to_vec()));
+            assert_eq!(store.find(&b"updated".to_vec()).unwrap().map(|(_, value)| value), Some(b"updated".to_vec()));
+            assert_eq!(store.verify().unwrap().valid_records, 101);
+        }
+    }
+
+    #[test]
+    fn the_index_knows_each_record_length() {
+        let file = TempFile::new("entry-len");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"a".to_vec(), &b"one".to_vec()).unwrap();
+        store.insert(&b"a".to_vec(), &b"three".to_vec()).unwrap();
+        store.bulk_insert(vec![(b"bb".to_vec(), vec![b'x'; 100])].into_iter()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(&b"ccc".to_vec(), &Vec::new());
+        store.apply_batch(batch).unwrap();
+        let expected = [(b"a".as_slice(), 1 + 5), (b"bb", 2 + 100), (b"ccc", 3)];
+        let check = |store: &AKVMEM| {
+            for (key, data_len) in expected {
+                assert_eq!(store.index[key].len, RECORD_HEADER_LEN + data_len);
+            }
+        };
+        check(&store);
+        store.compact().unwrap();
+        check(&store);
+        assert_eq!(store.get(&b"a".to_vec()).unwrap(), Some(b"three".to_vec()));
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        check(&store);
+        assert_eq!(store.get_typed(&b"bb".to_vec()).unwrap().unwrap().1, vec![b'x'; 100]);
+    }
+
+    #[test]
+    fn an_ordered_index_scans_prefixes_in_key_order() {
+        let file = TempFile::new("ordered");
+        let config = OpenConfig { index_kind: IndexKind::Ordered, ..OpenConfig::default() };
+        let mut store = open_with_options(&file.0, config).unwrap();
+        store.load().unwrap();
+        for key in ["user:3", "user:1", "session:1", "user:2", "users"] {
+            store.insert(&key.as_bytes().to_vec(), &b"value".to_vec()).unwrap();
+        }
+        store.delete(&b"user:2".to_vec()).unwrap();
+
+        let expected = vec![b"user:1".to_vec(), b"user:3".to_vec()];
+        assert_eq!(store.scan_prefix(b"user:").unwrap(), expected);
+        store.compact().unwrap();
+        assert_eq!(store.index.kind(), IndexKind::Ordered);
+        assert_eq!(store.scan_prefix(b"user:").unwrap(), expected);
+
+        let mut store = open_in_memory().unwrap();
+        store.insert(&b"user:1".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"other".to_vec(), &b"value".to_vec()).unwrap();
+        assert_eq!(store.scan_prefix(b"user:").unwrap(), vec![b"user:1".to_vec()]);
+    }
+
+    #[test]
+    fn unlinked_keys_are_gone_at_once_and_tombstoned_before_the_next_write() {
+        let file = TempFile::new("unlink");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"k1".to_vec(), &b"value".to_vec()).unwrap();
+        store.insert(&b"k2".to_vec(), &b"value".to_vec()).unwrap();
+        let len = store.file_size().unwrap();
+
+        assert_eq!(store.unlink(&[b"k1".to_vec(), b"missing".to_vec()]).unwrap(), 1);
+        assert_eq!(store.get(&b"k1".to_vec()).unwrap(), None);
+        assert_eq!(store.file_size().unwrap(), len);
+
+        // Setting the key again must not be undone by the pending tombstone.
+        store.insert(&b"k1".to_vec(), &b"new".to_vec()).unwrap();
+        assert_eq!(store.unlink(&[b"k2".to_vec()]).unwrap(), 1);
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(&b"k1".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(&b"k2".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn write_times_are_stored_and_survive_a_reload() {
+        let file = TempFile::new("writtenat");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let before = unix_millis();
+        store.insert(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+        let written_at = store.written_at(&b"key".to_vec()).unwrap().unwrap();
+        assert!(written_at >= before && written_at <= unix_millis());
+        assert_eq!(store.written_at(&b"missing".to_vec()).unwrap(), None);
+        drop(store);
+
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.written_at(&b"key".to_vec()).unwrap(), Some(written_at));
+        store.compact().unwrap();
+        assert_eq!(store.written_at(&b"key".to_vec()).unwrap(), Some(written_at));
+    }
+
+    #[test]
+    fn writes_over_maxmemory_fail_without_eviction() {
+        let file = TempFile::new("noeviction");
+        let config = OpenConfig { maxmemory: Some(RECORD_HEADER_LEN + 8), ..OpenConfig::default() };
+        let mut store = open_with_options(&file.0, config).unwrap();
+        store.load().unwrap();
+        store.insert(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+        // Overwriting in place still fits.
+        store.insert(&b"key".to_vec(), &b"other".to_vec()).unwrap();
+        let err = store.insert(&b"new".to_vec(), &b"value".to_vec()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+        assert!(err.to_string().starts_with("OOM"));
+    }
+
+    #[test]
+    fn a_damaged_record_header_fails_the_checksum() {
+        let file = TempFile::new("header-checksum");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let position = store.insert(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+        // Give the record an expiry it was never written with.
+        let mut f = OpenOptions::new().write(true).open(&file.0).unwrap();
+        f.seek(SeekFrom::Start(position + 4 + 8 + 8)).unwrap();
+        f.write_all(&[0xff]).unwrap();
+
+        assert_eq!(store.get_at(position).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_file_is_read_with_the_checksum_it_was_written_with() {
+        let file = TempFile::new("xxhash");
+        let config = OpenConfig { checksum: Checksum::XxHash, ..OpenConfig::default() };
+        let mut store = open_with_options(&file.0, config).unwrap();
+        store.load().unwrap();
+        store.insert(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+        drop(store);
+
+        // The header wins over what the caller asks for.
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.checksum, Checksum::XxHash);
+        assert_eq!(store.get(&b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn recover_cuts_off_a_damaged_tail() {
+        let file = TempFile::new("recover");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        store.insert(&b"kept".to_vec(), &b"value".to_vec()).unwrap();
+        let len = store.file_size().unwrap();
+        // The start of a record whose data never made it to disk.
+        let partial = encode_record(b"lost", b"value", None, unix_millis(), ValueType::String.to_flags(), Checksum::Crc32, Endianness::Little).unwrap();
+        store.f.write_all(&partial[..RECORD_HEADER_LEN as usize + 2]).unwrap();
+
+        assert_eq!(store.recover().unwrap(), RECORD_HEADER_LEN + 2);
+        assert_eq!(store.file_size().unwrap(), len);
+        assert_eq!(store.get(&b"kept".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.recover().unwrap(), 0);
+    }
+
+    #[test]
+    fn string_reads_of_a_hash_are_wrong_type() {
+        let file = TempFile::new("wrong-type");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let key = b"hash".to_vec();
+        store.hset(&key, &b"field".to_vec(), &b"value".to_vec()).unwrap();
+
+        assert_eq!(store.value_len(&key).unwrap_err().to_string(), WRONG_TYPE);
+        assert_eq!(store.get_range(&key, 0, 4).unwrap_err().to_string(), WRONG_TYPE);
+        assert_eq!(store.compare_and_swap(&key, &key, &key).unwrap_err().to_string(), WRONG_TYPE);
+    }
+
+    #[test]
+    fn hscan_pages_through_every_field_once() {
+        let mut store = open_in_memory().unwrap();
+        let key = b"hash".to_vec();
+        for field in &[b"a", b"b", b"c", b"d", b"e"] {
+            store.hset(&key, &field.to_vec(), &field.to_vec()).unwrap();
+        }
+
+        let (cursor, page) = store.hscan(&key, 0, 2).unwrap();
+        assert_eq!((cursor, page.len()), (2, 2));
+        let (cursor, second) = store.hscan(&key, cursor, 2).unwrap();
+        assert_eq!(cursor, 4);
+        let (cursor, last) = store.hscan(&key, cursor, 2).unwrap();
+        assert_eq!(cursor, 0);
+        let fields: Vec<_> = page.into_iter().chain(second).chain(last).map(|(field, _)| field).collect();
+        assert_eq!(fields, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+
+        assert_eq!(store.hscan(&key, 0, 5).unwrap().0, 0);
+        assert_eq!(store.hscan(&key, 9, 2).unwrap(), (0, Vec::new()));
+        assert_eq!(store.hscan(&b"missing".to_vec(), 0, 2).unwrap(), (0, Vec::new()));
+    }
+
+    #[test]
+    fn set_options_decide_whether_and_how_long() {
+        use server_helpers::SetOptions;
+
+        let mut store = open_in_memory().unwrap();
+        let key = b"key".to_vec();
+        let xx = SetOptions { only_if_present: true, ..SetOptions::default() };
+        assert!(!store.set_with_options(&key, &b"first".to_vec(), &xx).unwrap().0);
+        let nx = SetOptions { only_if_absent: true, ttl: Some(60_000), ..SetOptions::default() };
+        assert!(store.set_with_options(&key, &b"first".to_vec(), &nx).unwrap().0);
+        assert!(!store.set_with_options(&key, &b"second".to_vec(), &nx).unwrap().0);
+
+        let keep_ttl = SetOptions { keep_ttl: true, get: true, ..SetOptions::default() };
+        assert_eq!(store.set_with_options(&key, &b"second".to_vec(), &keep_ttl).unwrap(), (true, Some(b"first".to_vec())));
+        let kv = store.get_record(&key).unwrap().unwrap();
+        assert_eq!(kv.value, b"second".to_vec());
+        assert!(kv.expires_at.is_some());
+        assert!(store.set_with_options(&key, &b"third".to_vec(), &SetOptions::default()).unwrap().0);
+        assert_eq!(store.get_record(&key).unwrap().unwrap().expires_at, None);
+
+        let hash = b"hash".to_vec();
+        store.hset(&hash, &b"field".to_vec(), &b"value".to_vec()).unwrap();
+        let get = SetOptions { get: true, ..SetOptions::default() };
+        assert_eq!(store.set_with_options(&hash, &b"value".to_vec(), &get).unwrap_err().to_string(), WRONG_TYPE);
+    }
+
+    #[test]
+    fn get_del_hands_the_value_out_once() {
+        let mut store = open_in_memory().unwrap();
+        let key = b"key".to_vec();
+        store.insert(&key, &b"value".to_vec()).unwrap();
+
+        assert_eq!(store.get_del(&key).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get_del(&key).unwrap(), None);
+        assert_eq!(store.get(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn set_range_pads_and_splices() {
+        let file = TempFile::new("set-range");
+        let mut store = open(&file.0).unwrap();
+        store.load().unwrap();
+        let key = b"key".to_vec();
+        assert_eq!(store.set_range(&key, 0, &Vec::new()).unwrap(), 0);
+        assert_eq!(store.get(&key).unwrap(), None);
+
+        assert_eq!(store.set_range(&key, 2, &b"ab".to_vec()).unwrap(), 4);
+        assert_eq!(store.get(&key).unwrap(), Some(b"\0\0ab".to_vec()));
+        assert_eq!(store.set_range(&key, 1, &b"xy".to_vec()).unwrap(), 4);
+        assert_eq!(store.get(&key).unwrap(), Some(b"\0xyb".to_vec()));
+        assert_eq!(store.set_range(&key, u64::MAX, &b"z".to_vec()).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn incr_by_adds_to_an_integer_and_keeps_the_expiry() {
+        let mut store = open_in_memory().unwrap();
+        let key = b"counter".to_vec();
+        assert_eq!(store.incr_by(&key, 5).unwrap(), 5);
+        assert_eq!(store.incr_by(&key, -12).unwrap(), -7);
+        assert_eq!(store.get(&key).unwrap(), Some(b"-7".to_vec()));
+
+        let expires_at = unix_millis() + 60_000;
+        store.insert_with_expiry(&key, &b"10".to_vec(), Some(expires_at)).unwrap();
+        assert_eq!(store.incr_by(&key, 1).unwrap(), 11);
+        assert_eq!(store.get_record(&key).unwrap().unwrap().expires_at, Some(expires_at));
+
+        for value in ["", "1.5", " 1", "+1", "99999999999999999999"] {
+            store.insert(&key, &value.as_bytes().to_vec()).unwrap();
+            assert_eq!(store.incr_by(&key, 1).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        store.insert(&key, &i64::MAX.to_string().into_bytes()).unwrap();
+        assert_eq!(store.incr_by(&key, 1).unwrap_err().to_string(), "increment or decrement would overflow");
+        assert_eq!(store.get(&key).unwrap(), Some(i64::MAX.to_string().into_bytes()));
+    }
+
+    #[test]
+    fn incr_by_float_writes_the_shortest_exact_form() {
+        let mut store = open_in_memory().unwrap();
+        let key = b"gauge".to_vec();
+        assert_eq!(store.incr_by_float(&key, 10.5).unwrap(), b"10.5".to_vec());
+        assert_eq!(store.incr_by_float(&key, 0.1).unwrap(), b"10.6".to_vec());
+        assert_eq!(store.incr_by_float(&key, -10.6).unwrap(), b"0".to_vec());
+        for _ in 0..10 {
+            store.incr_by_float(&key, 0.1).unwrap();
+        }
+        assert_eq!(store.get(&key).unwrap(), Some(b"0.9999999999999999".to_vec()));
+        store.insert(&key, &b"5.0e3".to_vec()).unwrap();
+        assert_eq!(store.incr_by_float(&key, 2e15).unwrap(), b"2000000000005000".to_vec());
+        assert_eq!(store.incr_by_float(&key, -0.0).unwrap(), b"2000000000005000".to_vec());
+
+        for value in ["", "abc", " 1", "nan", "inf"] {
+            store.insert(&key, &value.as_bytes().to_vec()).unwrap();
+            assert_eq!(store.incr_by_float(&key, 1.0).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        store.insert(&key, &b"1".to_vec()).unwrap();
+        assert_eq!(store.incr_by_float(&key, f64::MAX).unwrap(), f64::MAX.to_string().into_bytes());
+        assert_eq!(
+            store.incr_by_float(&key, f64::MAX).unwrap_err().to_string(),
+            "increment would produce NaN or Infinity",
+        );
+    }
+}
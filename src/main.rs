@@ -1,14 +1,15 @@
 use std::io::prelude::*;
 
 const BYTES_PER_LINE: usize = 16;
-const INPUT: &'static [u8] = br#"
+const INPUT: &[u8] = br#"
 fn main() {
     println!("Hello world");
 }
 "#;
 fn main() {
     let mut buffer: Vec<u8> = vec![];
-    INPUT.read_to_end(&mut buffer).unwrap();
+    let mut input = INPUT;
+    input.read_to_end(&mut buffer).unwrap();
 
     let mut position_in_file = 0;
 
@@ -1,194 +1,349 @@
 use bytes::{Buf, Bytes, BytesMut};
+use std::convert::TryFrom;
 use std::io::{Cursor, Error, ErrorKind, Result};
+use std::str::FromStr;
 use futures::future::{BoxFuture, FutureExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    Integer(i64),
     Bulk(Bytes),
+    /// The null bulk string, `$-1`, e.g. GET of a missing key.
     Null,
+    /// The null array, `*-1`, which redis sends where an array reply has
+    /// nothing to give, e.g. a blocking pop that timed out.
+    NullArray,
     Array(Vec<Frame>),
 }
 
-pub struct Connection {
-    pub stream: TcpStream,
-    pub buf: BytesMut,
+/// Reads and writes frames over any byte stream: TCP, unix sockets, TLS or
+/// an in-memory `tokio::io::duplex` pipe.
+pub struct Connection<S> {
+    stream: S,
+    buf: BytesMut,
+    /// Set by CLIENT SETNAME, to tell connections apart when debugging.
+    name: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum Command {
     Get { key: String },
-    Set { key: String, value: String },
+    GetDel { key: String },
+    Set { key: String, value: String, options: SetOptions },
     Delete { key: String },
     Update { key: String, value: String },
+    Info,
+    Stats,
+    Verify,
+    GetRange { key: String, start: i64, end: i64 },
+    SetRange { key: String, offset: u64, value: String },
+    SetEx { key: String, seconds: u64, value: String },
+    Dump { key: String },
+    Restore { key: String, serialized_value: Vec<u8>, replace: bool },
+    HSet { key: String, field: String, value: String },
+    HGet { key: String, field: String },
+    HGetAll { key: String },
+    HScan { key: String, cursor: u64, count: usize },
+    HDel { key: String, field: String },
+    LPush { key: String, values: Vec<String> },
+    RPush { key: String, values: Vec<String> },
+    LRange { key: String, start: i64, stop: i64 },
+    LLen { key: String },
+    Expire { key: String, seconds: u64 },
+    PExpire { key: String, millis: u64 },
+    ExpireAt { key: String, unix_ts: u64 },
+    PTtl { key: String },
+    Persist { key: String },
+    Quit,
+    Auth { password: String },
+    Select { index: usize },
+    CommandInfo { subcommand: Option<String> },
+    Object { subcommand: String, key: String },
+    Wait { numreplicas: u64, timeout: u64 },
+    Cas { key: String, expected: String, new: String },
+    Subscribe { channels: Vec<String> },
+    Publish { channel: String, message: String },
+    Copy { src: String, dst: String, replace: bool },
+    RandomKey,
+    Touch { keys: Vec<String> },
+    Exists { keys: Vec<String> },
+    Unlink { keys: Vec<String> },
+    Debug { subcommand: String, args: Vec<String> },
+    Client { subcommand: String, args: Vec<String> },
+    IncrBy { key: String, delta: i64 },
+    DecrBy { key: String, delta: i64 },
+    IncrByFloat { key: String, delta: f64 },
+    /// Seconds to wait for a push, 0 for as long as it takes.
+    BlPop { keys: Vec<String>, timeout: f64 },
+    Export,
+    Import { payload: Vec<u8> },
 }
 
-pub fn get_line(src: &mut Cursor<&[u8]>) -> Result<Bytes> {
-    let len = src.remaining();
-    let mut buf: Vec<u8> = vec![];
-    for _ in 0..len {
-        let ch = src.get_u8();
-        if ch == b'\r' && src.has_remaining() {
-            let next = src.get_u8();
-            if next == b'\n' {
-                return Ok(Bytes::from(buf));
-            } else {
-                return Err(Error::new(ErrorKind::InvalidData, ""));
+/// The optional flags of SET, e.g. `SET key value EX 10 NX`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SetOptions {
+    /// Milliseconds until the key expires, from EX or PX.
+    pub ttl: Option<u64>,
+    /// NX: only set a key that doesn't exist.
+    pub only_if_absent: bool,
+    /// XX: only set a key that already exists.
+    pub only_if_present: bool,
+    /// KEEPTTL: leave an existing key's expiry as it is.
+    pub keep_ttl: bool,
+    /// GET: reply with the value the key held before, like GETSET.
+    pub get: bool,
+}
+
+impl SetOptions {
+    /// Parses the arguments after SET's key and value. Flags may come in any
+    /// order and case, but NX with XX or GET, or a TTL with KEEPTTL or
+    /// another TTL, is a syntax error.
+    fn parse(args: &[Frame]) -> Result<SetOptions> {
+        let syntax_error = || Error::new(ErrorKind::InvalidData, "syntax error");
+        let mut options = SetOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg {
+                Frame::Bulk(flag) => flag.to_ascii_lowercase(),
+                _ => return Err(syntax_error()),
+            };
+            match &flag[..] {
+                b"ex" | b"px" if options.ttl.is_none() && !options.keep_ttl => {
+                    let ttl: u64 = parse_arg(args.next())?;
+                    let ttl = if flag == b"ex" { ttl.checked_mul(1000) } else { Some(ttl) };
+                    options.ttl = Some(ttl.filter(|&ttl| ttl > 0).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidData, "invalid expire time in 'set' command")
+                    })?);
+                }
+                b"nx" if !options.only_if_present && !options.get => options.only_if_absent = true,
+                b"xx" if !options.only_if_absent => options.only_if_present = true,
+                b"keepttl" if options.ttl.is_none() => options.keep_ttl = true,
+                b"get" if !options.only_if_absent => options.get = true,
+                _ => return Err(syntax_error()),
             }
         }
-        buf.push(ch);
+        Ok(options)
+    }
+}
+
+/// Every command name `Command::from_frame` accepts, as listed by COMMAND.
+pub const COMMAND_NAMES: &[&str] = &[
+    "get", "getdel", "set", "delete", "update", "info", "stats", "verify", "getrange", "setrange", "setex", "dump", "restore",
+    "hset", "hget", "hgetall", "hdel", "lpush", "rpush", "lrange", "llen",
+    "expire", "expireat", "persist", "quit", "auth", "select", "command", "object", "wait",
+    "cas", "subscribe", "publish", "copy", "randomkey", "touch", "exists", "unlink", "debug", "client",
+    "incrby", "decrby", "incrbyfloat", "blpop", "export", "import", "pexpire", "pttl", "hscan",
+];
+
+/// The error `Frame::check` returns when the buffer ends part way through
+/// a frame. It means more bytes are needed, not that the client sent
+/// something malformed.
+fn incomplete() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "Incomplete frame")
+}
+
+/// Whether `err` is the `incomplete` signal rather than a protocol error.
+fn is_incomplete(err: &Error) -> bool {
+    err.kind() == ErrorKind::UnexpectedEof
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Protocol error: {}", msg))
+}
+
+/// Reads up to the next `\r\n`. Running out of bytes first is reported as
+/// `incomplete`, a lone `\r` inside the line as a protocol error.
+pub fn get_line(src: &mut Cursor<&[u8]>) -> Result<Bytes> {
+    next_line(src).map(Bytes::copy_from_slice)
+}
+
+/// `get_line` without the copy: the line borrowed from the buffer.
+fn next_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8]> {
+    let start = src.position() as usize;
+    let rest: &'a [u8] = src.get_ref().get(start..).unwrap_or_default();
+    let end = match rest.iter().position(|&ch| ch == b'\r') {
+        Some(end) => end,
+        None => return Err(incomplete()),
+    };
+    match rest.get(end + 1) {
+        None => Err(incomplete()),
+        Some(b'\n') => {
+            src.set_position((start + end + 2) as u64);
+            Ok(&rest[..end])
+        }
+        Some(_) => Err(protocol_error("expected \\n after \\r")),
+    }
+}
+
+/// Parses the ASCII digits of a length or integer straight from the
+/// buffer, without building a String first.
+fn parse_decimal(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |n, &digit| {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        n.checked_mul(10)?.checked_add((digit - b'0') as u64)
+    })
+}
+
+/// Parses the digits of an integer frame, which may be negative.
+fn parse_integer(line: &[u8]) -> Option<i64> {
+    match line.split_first() {
+        Some((b'-', digits)) => 0i64.checked_sub_unsigned(parse_decimal(digits)?),
+        _ => i64::try_from(parse_decimal(line)?).ok(),
+    }
+}
+
+/// Longest bulk string a client may send, as redis' default
+/// `proto-max-bulk-len`.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most elements an array frame may declare.
+pub const MAX_ARRAY_LEN: usize = 1024 * 1024;
+
+/// Reads a length line such as the `3` in `$3\r\n`. `-1` is None; any
+/// other negative, non-numeric or over `max` length is a protocol error.
+fn get_len(src: &mut Cursor<&[u8]>, max: usize) -> Result<Option<usize>> {
+    let line = next_line(src)?;
+    if line == b"-1" {
+        return Ok(None);
+    }
+    if line.first() == Some(&b'-') {
+        return Err(protocol_error("negative length"));
+    }
+    let len = parse_decimal(line)
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    match usize::try_from(len) {
+        Ok(len) if len <= max => Ok(Some(len)),
+        _ => Err(protocol_error("length exceeds the limit")),
+    }
+}
+
+/// Reads the `len` bytes of a bulk string and the `\r\n` after them. Going
+/// by the length lets values contain `\r\n` themselves.
+fn get_bulk(src: &mut Cursor<&[u8]>, len: usize) -> Result<Bytes> {
+    if src.remaining() < len.saturating_add(2) {
+        return Err(incomplete());
     }
-    Err(Error::new(ErrorKind::InvalidData, ""))
+    let data = src.copy_to_bytes(len);
+    if src.get_u8() != b'\r' || src.get_u8() != b'\n' {
+        return Err(protocol_error("bulk string is longer than its length"));
+    }
+    Ok(data)
 }
 
+/// Codes an error reply may already start with. Clients tell errors apart by
+/// this first word, so every reply needs one.
+const ERROR_CODES: &[&str] = &["ERR", "WRONGTYPE", "OOM", "NOAUTH", "WRONGPASS", "CROSSSLOT", "BUSYKEY"];
+
 impl Frame {
+    /// An error reply for `err`, prefixed with ERR unless it already starts
+    /// with one of the `ERROR_CODES`.
+    pub fn error<E: ToString>(err: E) -> Frame {
+        // Messages can quote what the client sent; a line break in one would
+        // end the error early and let the rest read as frames of its own.
+        let msg = err.to_string().replace(['\r', '\n'], " ");
+        let code = msg.split(' ').next().unwrap_or_default();
+        if ERROR_CODES.contains(&code) {
+            Frame::Error(msg)
+        } else {
+            Frame::Error(format!("ERR {}", msg))
+        }
+    }
+
+    /// Checks that `src` starts with a whole frame and moves past it.
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
         if !src.has_remaining() {
-            return  Err(Error::new(ErrorKind::InvalidData, ""))
+            return Err(incomplete())
         }
-        let res = match src.get_u8() {
-            b'+' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b':' => {
-                get_line(src)?;
+        match src.get_u8() {
+            b'+' | b':' | b'-' => {
+                next_line(src)?;
                 Ok(())
             }
             b'$' => {
-                let len_bytes = get_line(src)?;
-                let len_str = String::from_utf8_lossy(&len_bytes);
-                if len_str.eq("-1") {
-                    return Ok(())
-                }
-                let len = len_str.parse::<u32>();
-                let res = match len {
-                    Ok(len) => {
-                        let bytes = get_line(src)?;
-                        if bytes.len() == len as usize {
-                            return Ok(());
-                        }
-                        Err(Error::new(ErrorKind::InvalidData, ""))
-                    }
-                    Err(_) => Err(Error::new(ErrorKind::InvalidData, "")),
-                };
-                res
+                if let Some(len) = get_len(src, MAX_BULK_LEN)? {
+                    get_bulk(src, len)?;
+                }
+                Ok(())
             }
             b'*' => {
-                let len_bytes = get_line(src)?;
-                let len_str = String::from_utf8_lossy(&len_bytes);
-                if len_str.eq("-1") {
-                    return Ok(())
-                }
-                let len = len_str.parse::<u32>();
-
-                let res = match len {
-                    Ok(len) => {
-                        let mut res = Vec::<Result<()>>::with_capacity(len as usize);
-                        for _ in 0..len {
-                            let check = Frame::check(src);
-                            res.push(check);
-                        }
-                        let filtered = res
-                            .into_iter()
-                            .filter(|res| res.is_ok())
-                            .collect::<Vec<Result<()>>>();
-                        if filtered.len() == len as usize {
-                            return Ok(());
-                        }
-                        return Err(Error::new(ErrorKind::InvalidData, ""));
+                if let Some(len) = get_len(src, MAX_ARRAY_LEN)? {
+                    for _ in 0..len {
+                        Frame::check(src)?;
                     }
-                    Err(_) => Err(Error::new(ErrorKind::InvalidData, "")),
-                };
-                res
-            }
-            b'-' => {
-                get_line(src)?;
+                }
                 Ok(())
             }
-            _ => Err(Error::new(ErrorKind::InvalidData, "")),
-        };
-        res
+            _ => Err(protocol_error("unknown frame type")),
+        }
     }
+
+    /// Parses a frame that `check` has accepted.
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame> {
-        let res = match src.get_u8() {
+        if !src.has_remaining() {
+            return Err(incomplete())
+        }
+        match src.get_u8() {
             b'+' => {
                 let string = get_line(src)?;
                 Ok(Frame::Simple(String::from_utf8_lossy(&string).to_string()))
             }
-            b':' => {
-                let string = get_line(src)?;
-                let res = String::from_utf8_lossy(&string).to_string().parse::<u64>();
-                match res {
-                    Ok(int) => Ok(Frame::Integer(int)),
-                    Err(_) => Err(Error::new(ErrorKind::InvalidData, "")),
-                }
-            }
-            b'$' => {
-                let len_bytes = get_line(src)?;
-                let len_str = String::from_utf8_lossy(&len_bytes);
-                if len_str.eq("-1") {
-                    return Ok(Frame::Null);
-                }
-                let len = len_str.parse::<u32>();
-                let res = match len {
-                    Ok(len) => {
-                        let bytes = get_line(src)?;
-                        if bytes.len() == len as usize {
-                            return Ok(Frame::Bulk(bytes));
-                        }
-                        Err(Error::new(ErrorKind::InvalidData, ""))
-                    }
-                    Err(_) => Err(Error::new(ErrorKind::InvalidData, "")),
-                };
-                res
-            }
-            b'*' => {
-                let len_bytes = get_line(src)?;
-                let len_str = String::from_utf8_lossy(&len_bytes);
-                if len_str.eq("-1") {
-                    return Ok(Frame::Null);
-                }
-                let len = len_str.parse::<u32>();
-
-                let res = match len {
-                    Ok(len) => {
-                        let mut res = Vec::<Frame>::with_capacity(len as usize);
-                        for _ in 0..len {
-                            let check = Frame::parse(src)?;
-                            res.push(check);
-                        }
-                        Ok(Frame::Array(res))
+            b':' => match parse_integer(next_line(src)?) {
+                Some(int) => Ok(Frame::Integer(int)),
+                None => Err(protocol_error("invalid integer")),
+            },
+            b'$' => match get_len(src, MAX_BULK_LEN)? {
+                Some(len) => Ok(Frame::Bulk(get_bulk(src, len)?)),
+                None => Ok(Frame::Null),
+            },
+            b'*' => match get_len(src, MAX_ARRAY_LEN)? {
+                Some(len) => {
+                    // The length is the client's word; let the vector grow
+                    // rather than trusting it for the allocation.
+                    let mut res = Vec::<Frame>::new();
+                    for _ in 0..len {
+                        res.push(Frame::parse(src)?);
                     }
-                    Err(_) => Err(Error::new(ErrorKind::InvalidData, "")),
-                };
-                res
-            }
+                    Ok(Frame::Array(res))
+                }
+                None => Ok(Frame::NullArray),
+            },
             b'-' => {
                 let bytes = get_line(src)?;
                 Ok(Frame::Error(String::from_utf8_lossy(&bytes).to_string()))
             }
-            _ => Err(Error::new(ErrorKind::InvalidData, "")),
-        };
-        res
+            _ => Err(protocol_error("unknown frame type")),
+        }
     }
 }
 
 impl Command {
     pub fn from_frame(frame: &Frame) -> Result<Self> {
         match frame {
-            Frame::Array(fr) => match fr.get(0).unwrap() {
+            // Clients send command names in either case, e.g. COMMAND DOCS on
+            // connect, so match them lowercased.
+            Frame::Array(fr) => match &lowercase(fr.first().ok_or_else(|| Error::new(ErrorKind::InvalidData, "empty command"))?) {
                 Frame::Bulk(b) if String::from_utf8_lossy(b) == "get" => {
                     if let Some(Frame::Bulk(key)) = fr.get(1) {
                         return Ok(Command::Get {
                             key: String::from_utf8_lossy(key).to_string(),
                         });
                     }
-                    Err(Error::new(ErrorKind::InvalidData, ""))
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "getdel" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::GetDel {
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
                 }
                 Frame::Bulk(b) if String::from_utf8_lossy(b) == "set" => {
                     if let Some(Frame::Bulk(key)) = fr.get(1) {
@@ -196,11 +351,12 @@ impl Command {
                             return Ok(Command::Set {
                                 key: String::from_utf8_lossy(key).to_string(),
                                 value: String::from_utf8_lossy(value).to_string(),
+                                options: SetOptions::parse(&fr[3..])?,
                             });
                         }
-                        return Err(Error::new(ErrorKind::InvalidData, ""));
+                        return Err(wrong_arguments(b));
                     }
-                    Err(Error::new(ErrorKind::InvalidData, ""))
+                    Err(wrong_arguments(b))
                 }
                 Frame::Bulk(b) if String::from_utf8_lossy(b) == "delete" => {
                     if let Some(Frame::Bulk(key)) = fr.get(1) {
@@ -208,7 +364,7 @@ impl Command {
                             key: String::from_utf8_lossy(key).to_string(),
                         });
                     }
-                    Err(Error::new(ErrorKind::InvalidData, ""))
+                    Err(wrong_arguments(b))
                 }
                 Frame::Bulk(b) if String::from_utf8_lossy(b) == "update" => {
                     if let Some(Frame::Bulk(key)) = fr.get(1) {
@@ -218,55 +374,558 @@ impl Command {
                                 value: String::from_utf8_lossy(value).to_string(),
                             });
                         }
-                        return Err(Error::new(ErrorKind::InvalidData, ""));
+                        return Err(wrong_arguments(b));
+                    }
+                    Err(wrong_arguments(b))
+                }
+
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "info" => Ok(Command::Info),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "stats" => Ok(Command::Stats),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "verify" => Ok(Command::Verify),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "quit" => Ok(Command::Quit),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "randomkey" => Ok(Command::RandomKey),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "export" => Ok(Command::Export),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "import" => {
+                    if let Some(Frame::Bulk(payload)) = fr.get(1) {
+                        return Ok(Command::Import { payload: payload.to_vec() });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "command" => {
+                    let subcommand = match fr.get(1) {
+                        None => None,
+                        Some(Frame::Bulk(sub)) => Some(String::from_utf8_lossy(sub).to_lowercase()),
+                        Some(_) => return Err(wrong_arguments(b)),
+                    };
+                    Ok(Command::CommandInfo { subcommand })
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "object" => {
+                    if let (Some(Frame::Bulk(subcommand)), Some(Frame::Bulk(key))) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::Object {
+                            subcommand: String::from_utf8_lossy(subcommand).to_lowercase(),
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "debug" => {
+                    if let Some(Frame::Bulk(subcommand)) = fr.get(1) {
+                        return Ok(Command::Debug {
+                            subcommand: String::from_utf8_lossy(subcommand).to_lowercase(),
+                            args: bulk_strings(&fr[2..])?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "client" => {
+                    if let Some(Frame::Bulk(subcommand)) = fr.get(1) {
+                        return Ok(Command::Client {
+                            subcommand: String::from_utf8_lossy(subcommand).to_lowercase(),
+                            args: bulk_strings(&fr[2..])?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "cas" => {
+                    if let (Some(Frame::Bulk(key)), Some(Frame::Bulk(expected)), Some(Frame::Bulk(new))) = (fr.get(1), fr.get(2), fr.get(3)) {
+                        return Ok(Command::Cas {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            expected: String::from_utf8_lossy(expected).to_string(),
+                            new: String::from_utf8_lossy(new).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "subscribe" => {
+                    let channels = bulk_strings(&fr[1..])?;
+                    if channels.is_empty() {
+                        return Err(wrong_arguments(b));
+                    }
+                    Ok(Command::Subscribe { channels })
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "touch" => {
+                    let keys = bulk_strings(&fr[1..])?;
+                    if keys.is_empty() {
+                        return Err(wrong_arguments(b));
+                    }
+                    Ok(Command::Touch { keys })
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "exists" => {
+                    let keys = bulk_strings(&fr[1..])?;
+                    if keys.is_empty() {
+                        return Err(wrong_arguments(b));
+                    }
+                    Ok(Command::Exists { keys })
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "unlink" => {
+                    let keys = bulk_strings(&fr[1..])?;
+                    if keys.is_empty() {
+                        return Err(wrong_arguments(b));
+                    }
+                    Ok(Command::Unlink { keys })
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "publish" => {
+                    if let (Some(Frame::Bulk(channel)), Some(Frame::Bulk(message))) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::Publish {
+                            channel: String::from_utf8_lossy(channel).to_string(),
+                            message: String::from_utf8_lossy(message).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "copy" => {
+                    if let (Some(Frame::Bulk(src)), Some(Frame::Bulk(dst))) = (fr.get(1), fr.get(2)) {
+                        let replace = match fr.get(3) {
+                            None => false,
+                            Some(Frame::Bulk(flag)) if flag.eq_ignore_ascii_case(b"replace") => true,
+                            Some(_) => return Err(Error::new(ErrorKind::InvalidData, "syntax error")),
+                        };
+                        return Ok(Command::Copy {
+                            src: String::from_utf8_lossy(src).to_string(),
+                            dst: String::from_utf8_lossy(dst).to_string(),
+                            replace,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "wait" => Ok(Command::Wait {
+                    numreplicas: parse_arg(fr.get(1))?,
+                    timeout: parse_arg(fr.get(2))?,
+                }),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "select" => Ok(Command::Select {
+                    index: parse_arg(fr.get(1))?,
+                }),
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "auth" => {
+                    if let Some(Frame::Bulk(password)) = fr.get(1) {
+                        return Ok(Command::Auth {
+                            password: String::from_utf8_lossy(password).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "setex" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        let seconds = parse_arg(fr.get(2))?;
+                        if seconds == 0 {
+                            return Err(Error::new(ErrorKind::InvalidData, "invalid expire time in 'setex' command"));
+                        }
+                        if let Some(Frame::Bulk(value)) = fr.get(3) {
+                            return Ok(Command::SetEx {
+                                key: String::from_utf8_lossy(key).to_string(),
+                                seconds,
+                                value: String::from_utf8_lossy(value).to_string(),
+                            });
+                        }
+                        return Err(wrong_arguments(b));
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "dump" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::Dump {
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "restore" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        if let Some(Frame::Bulk(serialized_value)) = fr.get(2) {
+                            let replace = match fr.get(3) {
+                                None => false,
+                                Some(Frame::Bulk(flag)) if flag.eq_ignore_ascii_case(b"replace") => true,
+                                Some(_) => return Err(Error::new(ErrorKind::InvalidData, "syntax error")),
+                            };
+                            return Ok(Command::Restore {
+                                key: String::from_utf8_lossy(key).to_string(),
+                                serialized_value: serialized_value.to_vec(),
+                                replace,
+                            });
+                        }
+                        return Err(wrong_arguments(b));
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "getrange" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::GetRange {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            start: parse_arg(fr.get(2))?,
+                            end: parse_arg(fr.get(3))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "setrange" => {
+                    if let (Some(Frame::Bulk(key)), Some(Frame::Bulk(value))) = (fr.get(1), fr.get(3)) {
+                        return Ok(Command::SetRange {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            offset: parse_arg(fr.get(2))?,
+                            value: String::from_utf8_lossy(value).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "incrby" => {
+                    if let (Some(Frame::Bulk(key)), Some(_)) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::IncrBy {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            delta: parse_arg(fr.get(2))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "decrby" => {
+                    if let (Some(Frame::Bulk(key)), Some(_)) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::DecrBy {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            delta: parse_arg(fr.get(2))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "incrbyfloat" => {
+                    if let (Some(Frame::Bulk(key)), Some(Frame::Bulk(delta))) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::IncrByFloat {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            delta: String::from_utf8_lossy(delta).parse().ok()
+                                .filter(|delta: &f64| delta.is_finite())
+                                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "value is not a valid float"))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "hset" => {
+                    if let (Some(Frame::Bulk(key)), Some(Frame::Bulk(field)), Some(Frame::Bulk(value))) = (fr.get(1), fr.get(2), fr.get(3)) {
+                        return Ok(Command::HSet {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            field: String::from_utf8_lossy(field).to_string(),
+                            value: String::from_utf8_lossy(value).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "hget" => {
+                    if let (Some(Frame::Bulk(key)), Some(Frame::Bulk(field))) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::HGet {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            field: String::from_utf8_lossy(field).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "hgetall" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::HGetAll {
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "hscan" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        let cursor = parse_arg(fr.get(2))?;
+                        // COUNT is the only option; MATCH isn't supported.
+                        let count = match (fr.get(3), fr.len()) {
+                            (None, _) => 10,
+                            (Some(Frame::Bulk(option)), 5) if option.eq_ignore_ascii_case(b"count") => {
+                                match parse_arg(fr.get(4))? {
+                                    0 => return Err(Error::new(ErrorKind::InvalidData, "syntax error")),
+                                    count => count,
+                                }
+                            }
+                            _ => return Err(Error::new(ErrorKind::InvalidData, "syntax error")),
+                        };
+                        return Ok(Command::HScan {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            cursor,
+                            count,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "hdel" => {
+                    if let (Some(Frame::Bulk(key)), Some(Frame::Bulk(field))) = (fr.get(1), fr.get(2)) {
+                        return Ok(Command::HDel {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            field: String::from_utf8_lossy(field).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "lpush" || String::from_utf8_lossy(b) == "rpush" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        let values = bulk_strings(&fr[2..])?;
+                        if values.is_empty() {
+                            return Err(wrong_arguments(b));
+                        }
+                        let key = String::from_utf8_lossy(key).to_string();
+                        if String::from_utf8_lossy(b) == "lpush" {
+                            return Ok(Command::LPush { key, values });
+                        }
+                        return Ok(Command::RPush { key, values });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "blpop" => {
+                    if let [_, keys @ .., Frame::Bulk(timeout)] = &fr[..] {
+                        if !keys.is_empty() {
+                            let timeout: f64 = String::from_utf8_lossy(timeout).parse().ok()
+                                .filter(|timeout: &f64| timeout.is_finite())
+                                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "timeout is not a float or out of range"))?;
+                            if timeout < 0.0 {
+                                return Err(Error::new(ErrorKind::InvalidData, "timeout is negative"));
+                            }
+                            return Ok(Command::BlPop { keys: bulk_strings(keys)?, timeout });
+                        }
                     }
-                    Err(Error::new(ErrorKind::InvalidData, ""))
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "lrange" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::LRange {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            start: parse_arg(fr.get(2))?,
+                            stop: parse_arg(fr.get(3))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "llen" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::LLen {
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "expire" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::Expire {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            seconds: parse_arg(fr.get(2))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "pexpire" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::PExpire {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            millis: parse_arg(fr.get(2))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "pttl" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::PTtl {
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "expireat" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::ExpireAt {
+                            key: String::from_utf8_lossy(key).to_string(),
+                            unix_ts: parse_arg(fr.get(2))?,
+                        });
+                    }
+                    Err(wrong_arguments(b))
+                }
+                Frame::Bulk(b) if String::from_utf8_lossy(b) == "persist" => {
+                    if let Some(Frame::Bulk(key)) = fr.get(1) {
+                        return Ok(Command::Persist {
+                            key: String::from_utf8_lossy(key).to_string(),
+                        });
+                    }
+                    Err(wrong_arguments(b))
                 }
 
-                _ => Err(Error::new(ErrorKind::InvalidInput, "Unimplemented")),
+                Frame::Bulk(name) => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown command '{}'", String::from_utf8_lossy(name)),
+                )),
+                _ => Err(Error::new(ErrorKind::InvalidInput, "Protocol error: expected a command name")),
             },
 
-            _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid frame")),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Protocol error: expected an array of bulk strings")),
+        }
+    }
+
+    /// The command's name as clients send it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Get { .. } => "get",
+            Self::GetDel { .. } => "getdel",
+            Self::Set { .. } => "set",
+            Self::Delete { .. } => "delete",
+            Self::Update { .. } => "update",
+            Self::Info => "info",
+            Self::Stats => "stats",
+            Self::Verify => "verify",
+            Self::GetRange { .. } => "getrange",
+            Self::SetRange { .. } => "setrange",
+            Self::SetEx { .. } => "setex",
+            Self::Dump { .. } => "dump",
+            Self::Restore { .. } => "restore",
+            Self::HSet { .. } => "hset",
+            Self::HGet { .. } => "hget",
+            Self::HGetAll { .. } => "hgetall",
+            Self::HScan { .. } => "hscan",
+            Self::HDel { .. } => "hdel",
+            Self::LPush { .. } => "lpush",
+            Self::RPush { .. } => "rpush",
+            Self::LRange { .. } => "lrange",
+            Self::LLen { .. } => "llen",
+            Self::Expire { .. } => "expire",
+            Self::PExpire { .. } => "pexpire",
+            Self::ExpireAt { .. } => "expireat",
+            Self::PTtl { .. } => "pttl",
+            Self::Persist { .. } => "persist",
+            Self::Quit => "quit",
+            Self::Auth { .. } => "auth",
+            Self::Select { .. } => "select",
+            Self::CommandInfo { .. } => "command",
+            Self::Object { .. } => "object",
+            Self::Wait { .. } => "wait",
+            Self::Cas { .. } => "cas",
+            Self::Subscribe { .. } => "subscribe",
+            Self::Publish { .. } => "publish",
+            Self::Copy { .. } => "copy",
+            Self::RandomKey => "randomkey",
+            Self::Touch { .. } => "touch",
+            Self::Exists { .. } => "exists",
+            Self::Unlink { .. } => "unlink",
+            Self::Debug { .. } => "debug",
+            Self::Client { .. } => "client",
+            Self::IncrBy { .. } => "incrby",
+            Self::DecrBy { .. } => "decrby",
+            Self::IncrByFloat { .. } => "incrbyfloat",
+            Self::BlPop { .. } => "blpop",
+            Self::Export => "export",
+            Self::Import { .. } => "import",
         }
     }
 
     pub fn key(&self) -> Option<String> {
         match self {
-            Self::Get { key } | Self::Delete { key } => Some(key.clone()),
-            Self::Set { key, value: _ } | Self::Update { key, value: _ } => Some(key.clone()),
+            Self::Get { key } | Self::GetDel { key } | Self::Delete { key } | Self::Dump { key } => Some(key.clone()),
+            Self::Set { key, .. } | Self::Update { key, value: _ } => Some(key.clone()),
+            Self::GetRange { key, .. } | Self::SetRange { key, .. } | Self::SetEx { key, .. } | Self::Restore { key, .. } => Some(key.clone()),
+            Self::HSet { key, .. } | Self::HGet { key, .. } | Self::HGetAll { key } | Self::HDel { key, .. } => Some(key.clone()),
+            Self::HScan { key, .. } => Some(key.clone()),
+            Self::LPush { key, .. } | Self::RPush { key, .. } | Self::LRange { key, .. } | Self::LLen { key } => Some(key.clone()),
+            Self::Expire { key, .. } | Self::ExpireAt { key, .. } | Self::Persist { key } => Some(key.clone()),
+            Self::PExpire { key, .. } | Self::PTtl { key } => Some(key.clone()),
+            Self::Object { key, .. } | Self::Cas { key, .. } => Some(key.clone()),
+            Self::IncrBy { key, .. } | Self::DecrBy { key, .. } | Self::IncrByFloat { key, .. } => Some(key.clone()),
+            // The key that gets written.
+            Self::Copy { dst, .. } => Some(dst.clone()),
+            Self::Info | Self::Stats | Self::Verify | Self::Quit | Self::Auth { .. } | Self::Select { .. } | Self::CommandInfo { .. } => None,
+            Self::Wait { .. } | Self::Subscribe { .. } | Self::Publish { .. } | Self::RandomKey | Self::Touch { .. } => None,
+            Self::Exists { .. } | Self::Unlink { .. } | Self::Debug { .. } | Self::Client { .. } | Self::BlPop { .. } => None,
+            Self::Export | Self::Import { .. } => None,
         }
     }
 
     pub fn value(&self) -> Option<String> {
         match self {
-            Self::Get { key: _ } | Self::Delete { key: _ } => None,
-            Self::Set { key: _, value} | Self::Update { key:_, value} => Some(value.clone()),
+            Self::Get { key: _ } | Self::GetDel { key: _ } | Self::Delete { key: _ } => None,
+            Self::Set { value, .. } | Self::Update { key:_, value} => Some(value.clone()),
+            Self::SetEx { value, .. } | Self::SetRange { value, .. } | Self::HSet { value, .. } => Some(value.clone()),
+            Self::Cas { new, .. } => Some(new.clone()),
+            Self::Publish { message, .. } => Some(message.clone()),
+            Self::Subscribe { .. } | Self::Copy { .. } | Self::RandomKey | Self::Touch { .. } | Self::Exists { .. } | Self::Unlink { .. } | Self::Debug { .. } => None,
+            Self::Client { .. } | Self::IncrBy { .. } | Self::DecrBy { .. } | Self::IncrByFloat { .. } | Self::BlPop { .. } => None,
+            Self::Export | Self::Import { .. } => None,
+            Self::HGet { .. } | Self::HGetAll { .. } | Self::HScan { .. } | Self::HDel { .. } => None,
+            Self::LPush { .. } | Self::RPush { .. } | Self::LRange { .. } | Self::LLen { .. } => None,
+            Self::Expire { .. } | Self::ExpireAt { .. } | Self::Persist { .. } | Self::Quit | Self::Auth { .. } | Self::Select { .. } => None,
+            Self::PExpire { .. } | Self::PTtl { .. } => None,
+            Self::CommandInfo { .. } | Self::Object { .. } | Self::Wait { .. } => None,
+            Self::Info | Self::Stats | Self::Verify | Self::GetRange { .. } | Self::Dump { .. } | Self::Restore { .. } => None,
         }
     }
 }
 
-impl Connection {
+fn lowercase(frame: &Frame) -> Frame {
+    match frame {
+        Frame::Bulk(b) => Frame::Bulk(Bytes::from(b.to_ascii_lowercase())),
+        other => other.clone(),
+    }
+}
+
+/// The error for a command called with too few or malformed arguments.
+fn wrong_arguments(name: &[u8]) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("wrong number of arguments for '{}' command", String::from_utf8_lossy(name)),
+    )
+}
+
+/// The remaining arguments of a variadic command such as LPUSH.
+fn bulk_strings(frames: &[Frame]) -> Result<Vec<String>> {
+    frames.iter().map(|frame| match frame {
+        Frame::Bulk(b) => Ok(String::from_utf8_lossy(b).to_string()),
+        _ => Err(Error::new(ErrorKind::InvalidData, "syntax error")),
+    }).collect()
+}
+
+/// Parses a numeric command argument such as a GETRANGE offset.
+fn parse_arg<T: FromStr>(frame: Option<&Frame>) -> Result<T> {
+    match frame {
+        Some(Frame::Bulk(b)) => String::from_utf8_lossy(b).parse::<T>().map_err(|_| {
+            Error::new(ErrorKind::InvalidData, "value is not an integer or out of range")
+        }),
+        _ => Err(Error::new(ErrorKind::InvalidData, "syntax error")),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            buf: BytesMut::with_capacity(4096),
+            name: None,
+        }
+    }
+
+    /// The name the client gave this connection, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Names the connection, or clears the name if `name` is None.
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name;
+    }
+
+    /// Reads the next frame, waiting for more bytes while the buffer holds
+    /// only part of one. A malformed frame is returned as an InvalidData
+    /// error; the buffer is left as it was, so the caller should close the
+    /// connection.
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
-            if let Ok(Some(frame)) = self.parse_frame() {
+            if let Some(frame) = self.parse_frame()? {
                 return Ok(Some(frame));
             }
-            match self.stream.read_buf(&mut self.buf).await? {
-                n => {
-                    if n == 0 && self.buf.is_empty() {
-                        return Ok(None)
-                    } else if n == 0 && !self.buf.is_empty() {
-                        return Err(Error::new(
-                            ErrorKind::ConnectionReset,
-                            "Connection reset by peer",
-                        ))
-                    }
-                }
+            let n = self.stream.read_buf(&mut self.buf).await?;
+            if n == 0 && self.buf.is_empty() {
+                return Ok(None)
+            } else if n == 0 && !self.buf.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::ConnectionReset,
+                    "Connection reset by peer",
+                ))
             }
         }
  
     }
 
+    /// Takes the next frame off the buffer. Ok(None) means the buffer does
+    /// not hold a whole frame yet; an error means it never will.
     pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
         let mut src = Cursor::new(&self.buf[..]);
         match Frame::check(&mut src) {
@@ -280,6 +939,7 @@ impl Connection {
 
                 Ok(Some(frame))
             }
+            Err(err) if is_incomplete(&err) => Ok(None),
             Err(err) => Err(err),
         }
     }
@@ -289,40 +949,38 @@ impl Connection {
             match frame {
                 Frame::Array(vec) => {
                     let len = vec.len();
-                    self.stream.write(format!("*{}\r\n", len).as_bytes()).await?;
+                    self.stream.write_all(format!("*{}\r\n", len).as_bytes()).await?;
+                    // The elements follow the header with nothing after them.
                     for fr in vec {
                         self.write_frame(fr).await?;
                     }
-                    self.stream.write("\r\n".as_bytes()).await?;
                 }
                 Frame::Bulk(bytes) => {
                     let len = bytes.len();
-                    self.stream.write(format!("${}\r\n", len).as_bytes()).await?;
-                    for byte in bytes {
-                        self.stream.write(&[byte]).await?;
-                    } 
-                    self.stream.write("\r\n".as_bytes()).await?;
+                    self.stream.write_all(format!("${}\r\n", len).as_bytes()).await?;
+                    self.stream.write_all(&bytes).await?;
+                    self.stream.write_all("\r\n".as_bytes()).await?;
                 }
                 Frame::Error(err) => {
-                    let bytes = err.as_bytes();
-                    self.stream.write("-\r\n".as_bytes()).await?;
-                    self.stream.write(bytes).await?;
-                    self.stream.write("\r\n".as_bytes()).await?;
+                    self.stream.write_all(format!("-{}\r\n", err.replace(['\r', '\n'], " ")).as_bytes()).await?;
                 }
                 Frame::Integer(int) => {
-                    self.stream.write(format!(":{}\r\n", int).as_bytes()).await?; 
+                    self.stream.write_all(format!(":{}\r\n", int).as_bytes()).await?; 
                 }
                 Frame::Null => {
-                    self.stream.write("\0\r\n".as_bytes()).await?;
+                    self.stream.write_all(b"$-1\r\n").await?;
+                }
+                Frame::NullArray => {
+                    self.stream.write_all(b"*-1\r\n").await?;
                 }
                 Frame::Simple(msg) => {
                     let bytes = msg.as_bytes();
-                    self.stream.write("+".as_bytes()).await?;
-                    self.stream.write(bytes).await?;
-                    self.stream.write("\r\n".as_bytes()).await?;
+                    self.stream.write_all("+".as_bytes()).await?;
+                    self.stream.write_all(bytes).await?;
+                    self.stream.write_all("\r\n".as_bytes()).await?;
                 }
             }
-            self.stream.flush().await.unwrap();
+            self.stream.flush().await?;
             Ok(())
         }.boxed()
     }
@@ -330,3 +988,160 @@ impl Connection {
 }
 
     
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks and parses a frame the way `Connection::parse_frame` does.
+    fn parse(bytes: &[u8]) -> Result<Frame> {
+        Frame::check(&mut Cursor::new(bytes))?;
+        Frame::parse(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn an_empty_array_is_not_a_command() {
+        let frame = parse(b"*0\r\n").unwrap();
+        assert!(matches!(&frame, Frame::Array(items) if items.is_empty()));
+        assert_eq!(Command::from_frame(&frame).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn only_minus_one_is_a_null_length() {
+        assert!(matches!(parse(b"$-1\r\n").unwrap(), Frame::Null));
+        let too_long = format!("${}\r\n", MAX_BULK_LEN + 1);
+        for bytes in [&b"$-2\r\n"[..], b"$abc\r\n", b"$\r\n", b"*-5\r\n", too_long.as_bytes()] {
+            let err = parse(bytes).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[tokio::test]
+    async fn both_nulls_round_trip() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        server.write_frame(Frame::Null).await.unwrap();
+        server.write_frame(Frame::NullArray).await.unwrap();
+        assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::Null)));
+        assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::NullArray)));
+        assert!(matches!(parse(b"*-1\r\n").unwrap(), Frame::NullArray));
+    }
+
+    #[tokio::test]
+    async fn a_command_name_with_crlf_comes_back_as_one_error_line() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        let err = Command::from_frame(&parse(b"*1\r\n$10\r\nx\r\n+OK\r\n:1\r\n").unwrap()).unwrap_err();
+        server.write_frame(Frame::error(err)).await.unwrap();
+        server.write_frame(Frame::Error("ERR two\r\n:2".to_string())).await.unwrap();
+        server.write_frame(Frame::Integer(7)).await.unwrap();
+
+        assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::Error(msg)) if msg == "ERR unknown command 'x  +ok  :1'"));
+        assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::Error(msg)) if msg == "ERR two  :2"));
+        assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::Integer(7))));
+    }
+
+    #[tokio::test]
+    async fn a_frame_split_across_reads_comes_back_whole() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut server = Connection::new(server);
+        client.write_all(b"*2\r\n$3\r\nget\r\n$").await.unwrap();
+        let rest = async {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            client.write_all(b"3\r\nkey\r\n").await.unwrap();
+        };
+        let (frame, ()) = tokio::join!(server.read_frame(), rest);
+        assert!(matches!(Command::from_frame(&frame.unwrap().unwrap()).unwrap(), Command::Get { key } if key == "key"));
+    }
+
+    #[tokio::test]
+    async fn a_malformed_frame_fails_without_waiting_for_more() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut server = Connection::new(server);
+        // The client stays connected, so only the parse can end the read.
+        client.write_all(b"*1\r\n$abc\r\n").await.unwrap();
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), server.read_frame()).await.unwrap();
+        assert_eq!(read.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn nested_arrays_round_trip() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        let nested = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("outer")),
+            Frame::Array(vec![Frame::Integer(1), Frame::Array(vec![]), Frame::Simple("OK".to_string())]),
+            Frame::Null,
+        ]);
+        server.write_frame(nested).await.unwrap();
+        server.write_frame(Frame::Integer(7)).await.unwrap();
+        match client.read_frame().await.unwrap() {
+            Some(Frame::Array(items)) => {
+                assert!(matches!(&items[..], [Frame::Bulk(_), Frame::Array(inner), Frame::Null]
+                    if matches!(&inner[..], [Frame::Integer(1), Frame::Array(empty), Frame::Simple(_)] if empty.is_empty())));
+            }
+            frame => panic!("read {:?}", frame),
+        }
+        // Nothing was left over between the two frames.
+        assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::Integer(7))));
+    }
+
+    #[tokio::test]
+    async fn an_error_is_written_on_one_line() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut client = Connection::new(client);
+        client.write_frame(Frame::Error("ERR boom".to_string())).await.unwrap();
+        drop(client);
+        let mut written = Vec::new();
+        server.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"-ERR boom\r\n");
+    }
+
+    #[tokio::test]
+    async fn errors_round_trip_with_a_code() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        let missing_key = Command::from_frame(&Frame::Array(vec![Frame::Bulk(Bytes::from("get"))])).unwrap_err();
+        server.write_frame(Frame::error(missing_key)).await.unwrap();
+        server.write_frame(Frame::error(Error::new(ErrorKind::InvalidInput, crate::WRONG_TYPE))).await.unwrap();
+        for expected in ["ERR wrong number of arguments for 'get' command", crate::WRONG_TYPE] {
+            match client.read_frame().await.unwrap() {
+                Some(Frame::Error(msg)) => assert_eq!(msg, expected),
+                frame => panic!("read {:?}", frame),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn negative_integers_round_trip() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        for int in [-7, i64::MIN, i64::MAX] {
+            server.write_frame(Frame::Integer(int)).await.unwrap();
+            assert!(matches!(client.read_frame().await.unwrap(), Some(Frame::Integer(read)) if read == int));
+        }
+        assert_eq!(parse_integer(b"-"), None);
+        assert_eq!(parse_integer(b"9223372036854775808"), None);
+    }
+
+    #[test]
+    fn set_flags_are_parsed_in_any_order() {
+        let set = |args: &[&str]| {
+            let frames = ["set", "key", "value"].iter().chain(args)
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect();
+            Command::from_frame(&Frame::Array(frames)).map(|cmd| match cmd {
+                Command::Set { options, .. } => options,
+                cmd => panic!("parsed as {:?}", cmd),
+            })
+        };
+        assert_eq!(set(&[]).unwrap(), SetOptions::default());
+        let options = set(&["nx", "EX", "10"]).unwrap();
+        assert_eq!((options.ttl, options.only_if_absent), (Some(10_000), true));
+        assert_eq!(set(&["PX", "5", "xx"]).unwrap().ttl, Some(5));
+        assert!(set(&["GET", "xx"]).unwrap().get);
+        for bad in [&["nx", "xx"][..], &["nx", "get"], &["ex", "1", "px", "1"], &["ex", "1", "keepttl"], &["ex", "0"], &["ex"], &["bogus"]] {
+            assert_eq!(set(bad).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", bad);
+        }
+    }
+}
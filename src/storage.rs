@@ -0,0 +1,284 @@
+//! Where a store's log lives. `AKVMEM` only needs positioned reads, appends
+//! and truncation from it, plus a way to swap in a compacted copy, so any
+//! backend that can do those works. `File` is the one `open` uses; `Memory`
+//! backs stores opened with `open_in_memory`.
+
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A log `AKVMEM` can be kept in. Reads go through `read_at` so that they
+/// never move the cursor appends are written at; writes go through `Write`
+/// after `seek_end`.
+pub trait Storage: Write + Sized {
+    /// Reads into `buf` starting at `offset`. Returns how many bytes were
+    /// read, which is 0 at or past the end.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
+
+    /// Length of the log in bytes, header included.
+    fn size(&self) -> Result<u64>;
+
+    /// Moves the write cursor to the end and returns the offset the next
+    /// write lands at.
+    fn seek_end(&mut self) -> Result<u64>;
+
+    /// Cuts the log back to `len` bytes.
+    fn set_len(&mut self, len: u64) -> Result<()>;
+
+    /// Makes everything written so far durable, metadata included.
+    fn sync_all(&self) -> Result<()>;
+
+    /// Like `sync_all`, but may skip metadata not needed to read the data
+    /// back.
+    fn sync_data(&self) -> Result<()>;
+
+    /// A handle for a compaction to read the first `end` bytes of the log
+    /// through while the store keeps appending to this one.
+    fn reader(&self, path: &Path, end: u64) -> Result<Self>;
+
+    /// Empty storage for a compaction of the store at `path` to write into.
+    fn scratch(path: &Path) -> Result<Self>;
+
+    /// Makes `compacted`, filled in from `scratch(path)`, the log in place
+    /// of this one.
+    fn replace(&mut self, compacted: Self, path: &Path) -> Result<()>;
+
+    /// Cleans up after a compaction of the store at `path` that was
+    /// abandoned before `replace`.
+    fn discard_scratch(path: &Path);
+
+    /// Sets aside room for `bytes` more of log without changing its length,
+    /// so a big load grows into space that is already allocated. Only a
+    /// hint; the default does nothing.
+    fn reserve(&mut self, _bytes: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gives back whatever `reserve` set aside that is still unused.
+    fn release_reserved(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Picks up a log that was swapped out from under the store at `path`,
+    /// e.g. by an external compaction.
+    fn reopen(&mut self, path: &Path, writable: bool) -> Result<()>;
+
+    /// Whether `path` still leads to this log, rather than to nothing or to
+    /// a file that has taken its place. The default, for logs without a
+    /// path, always says yes.
+    fn is_at(&self, _path: &Path) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Takes the advisory lock that keeps a second writer, e.g. another server
+/// started on the same file, from interleaving its records with ours. Fails
+/// with WouldBlock if another handle already holds it.
+pub(crate) fn lock_exclusive(f: &File) -> Result<()> {
+    f.try_lock().map_err(|err| match err {
+        TryLockError::WouldBlock => Error::new(
+            ErrorKind::WouldBlock,
+            "Data file is locked by another writer; is another server running on it?",
+        ),
+        TryLockError::Error(err) => err,
+    })
+}
+
+/// Where a compaction of the data file at `path` is written before it
+/// replaces it.
+fn scratch_path(path: &Path) -> PathBuf {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".compact");
+    PathBuf::from(tmp_path)
+}
+
+impl Storage for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(self, buf, offset)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(self, buf, offset)?;
+        Ok(n)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn seek_end(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        File::sync_all(self)
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        File::sync_data(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reserve(&mut self, bytes: u64) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let offset = libc::off_t::try_from(self.size()?);
+        let len = libc::off_t::try_from(bytes);
+        let (offset, len) = match (offset, len) {
+            (Ok(offset), Ok(len)) if len > 0 => (offset, len),
+            _ => return Ok(()),
+        };
+        // KEEP_SIZE allocates the blocks without moving the end of the file,
+        // so appends and loads carry on as if nothing had been reserved.
+        // SAFETY: fallocate only touches the file behind the descriptor,
+        // which stays open for the length of the call.
+        if unsafe { libc::fallocate(self.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset, len) } != 0 {
+            let err = Error::last_os_error();
+            // Filesystems without preallocation just grow as they go.
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    fn release_reserved(&mut self) -> Result<()> {
+        // Truncating to the current length frees the blocks past it.
+        let len = self.size()?;
+        File::set_len(self, len)
+    }
+
+    fn reader(&self, path: &Path, _end: u64) -> Result<Self> {
+        File::open(path)
+    }
+
+    fn scratch(path: &Path) -> Result<Self> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(scratch_path(path))
+    }
+
+    fn replace(&mut self, _compacted: Self, path: &Path) -> Result<()> {
+        fs::rename(scratch_path(path), path)?;
+        // The lock belongs to the old file, so take it again on the new one.
+        let f = OpenOptions::new().read(true).append(true).open(path)?;
+        lock_exclusive(&f)?;
+        *self = f;
+        Ok(())
+    }
+
+    fn discard_scratch(path: &Path) {
+        // Once the swap succeeds the temporary file has been renamed away,
+        // so this only cleans up after an abandoned or failed compaction.
+        let _ = fs::remove_file(scratch_path(path));
+    }
+
+    fn reopen(&mut self, path: &Path, writable: bool) -> Result<()> {
+        let f = OpenOptions::new().read(true).append(writable).open(path)?;
+        if writable {
+            lock_exclusive(&f)?;
+        }
+        *self = f;
+        Ok(())
+    }
+
+    fn is_at(&self, path: &Path) -> Result<bool> {
+        let at_path = match fs::metadata(path) {
+            Ok(at_path) => at_path,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let open = self.metadata()?;
+            Ok(open.dev() == at_path.dev() && open.ino() == at_path.ino())
+        }
+        // Without inode numbers a replaced file can't be told apart, but a
+        // deleted one can.
+        #[cfg(not(unix))]
+        {
+            let _ = at_path;
+            Ok(true)
+        }
+    }
+}
+
+/// A log kept in a buffer. Nothing is durable, so syncing is a no-op, and
+/// there is no file to reopen.
+#[derive(Debug, Default)]
+pub struct Memory(Vec<u8>);
+
+impl Write for Memory {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for Memory {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(self.0.len());
+        let n = buf.len().min(self.0.len() - start);
+        buf[..n].copy_from_slice(&self.0[start..start + n]);
+        Ok(n)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+
+    fn seek_end(&mut self) -> Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.0.truncate(usize::try_from(len).unwrap_or(usize::MAX));
+        Ok(())
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn reader(&self, _path: &Path, end: u64) -> Result<Self> {
+        let end = usize::try_from(end).unwrap_or(usize::MAX).min(self.0.len());
+        Ok(Memory(self.0[..end].to_vec()))
+    }
+
+    fn scratch(_path: &Path) -> Result<Self> {
+        Ok(Memory::default())
+    }
+
+    fn replace(&mut self, compacted: Self, _path: &Path) -> Result<()> {
+        *self = compacted;
+        Ok(())
+    }
+
+    fn reserve(&mut self, bytes: u64) -> Result<()> {
+        self.0.reserve(usize::try_from(bytes).unwrap_or(usize::MAX).min(isize::MAX as usize - self.0.len()));
+        Ok(())
+    }
+
+    fn release_reserved(&mut self) -> Result<()> {
+        self.0.shrink_to_fit();
+        Ok(())
+    }
+
+    fn discard_scratch(_path: &Path) {}
+
+    fn reopen(&mut self, _path: &Path, _writable: bool) -> Result<()> {
+        Ok(())
+    }
+}